@@ -0,0 +1,165 @@
+//! Throughput of bursts of small messages under each flush policy.
+//!
+//! Run with `cargo bench --features unstable` on a nightly compiler.
+
+#![cfg(feature = "unstable")]
+#![feature(test)]
+
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate test;
+extern crate tokio_core;
+
+use std::time::Duration;
+use futures::{Future, Stream};
+use futures::future::{join_all, ok, FutureResult};
+use msgpack_rpc::{Client, Connection, EndpointBuilder, FlushPolicy, Handler, RequestContext,
+                  Value};
+use msgpack_rpc::io::{PipeOptions, Server};
+use test::Bencher;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+const BURST: usize = 1000;
+
+/// A handler which responds with the parameters of each request.
+struct Echo;
+
+impl Handler for Echo {
+    type RequestFuture = FutureResult<Value, Value>;
+    type NotifyFuture = FutureResult<(), ()>;
+
+    fn handle_request(
+        &self,
+        _: &str,
+        params: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::RequestFuture {
+        ok(params)
+    }
+
+    fn handle_notification(
+        &self,
+        _: &str,
+        _: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::NotifyFuture {
+        ok(())
+    }
+}
+
+fn batched() -> FlushPolicy {
+    FlushPolicy::Batched {
+        bytes: 16 * 1024,
+        max_delay: Duration::from_millis(1),
+    }
+}
+
+fn window() -> FlushPolicy {
+    FlushPolicy::Window(Duration::from_millis(1))
+}
+
+/// Send a burst of requests, and wait for all of the responses.
+fn burst(core: &mut Core, client: &Client) {
+    let requests: Vec<_> = (0..BURST)
+        .map(|i| client.request("echo", vec![Value::from(i)]))
+        .collect();
+    core.run(join_all(requests)).unwrap();
+}
+
+/// Send a burst of notifications, and wait until all of them are written.
+fn notify_burst(core: &mut Core, client: &Client) {
+    let acks: Vec<_> = (0..BURST)
+        .map(|i| client.notify("echo", vec![Value::from(i)]))
+        .collect();
+    core.run(join_all(acks)).unwrap();
+}
+
+fn pair(core: &Core, policy: FlushPolicy) -> Client {
+    let handle = core.handle();
+    let builder = EndpointBuilder::new().flush_policy(policy);
+    let (server, client) = builder.pair(&handle, PipeOptions::new());
+    server.serve(&handle, Echo);
+    client.into_client()
+}
+
+fn tcp(core: &mut Core, policy: FlushPolicy) -> Client {
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let builder = EndpointBuilder::new().flush_policy(policy);
+    let (server, _) = Server::new(|_: &Connection, _: &Client| Echo)
+        .endpoint(builder.clone())
+        .serve_incoming(
+            &handle,
+            listener.incoming().map(|(sock, addr)| (sock, Some(addr))),
+        );
+    handle.spawn(server.map_err(|_| ()));
+    let endpoint = core.run(builder.connect_tcp(&handle, &addr)).unwrap();
+    endpoint.into_client()
+}
+
+fn bench_pair(b: &mut Bencher, policy: FlushPolicy) {
+    let mut core = Core::new().unwrap();
+    let client = pair(&core, policy);
+    b.iter(|| burst(&mut core, &client));
+}
+
+fn bench_tcp(b: &mut Bencher, policy: FlushPolicy) {
+    let mut core = Core::new().unwrap();
+    let client = tcp(&mut core, policy);
+    b.iter(|| burst(&mut core, &client));
+}
+
+fn bench_tcp_notifications(b: &mut Bencher, policy: FlushPolicy) {
+    let mut core = Core::new().unwrap();
+    let client = tcp(&mut core, policy);
+    b.iter(|| notify_burst(&mut core, &client));
+}
+
+#[bench]
+fn pair_requests_immediate(b: &mut Bencher) {
+    bench_pair(b, FlushPolicy::Immediate);
+}
+
+#[bench]
+fn pair_requests_batched(b: &mut Bencher) {
+    bench_pair(b, batched());
+}
+
+#[bench]
+fn pair_requests_window(b: &mut Bencher) {
+    bench_pair(b, window());
+}
+
+#[bench]
+fn tcp_requests_immediate(b: &mut Bencher) {
+    bench_tcp(b, FlushPolicy::Immediate);
+}
+
+#[bench]
+fn tcp_requests_batched(b: &mut Bencher) {
+    bench_tcp(b, batched());
+}
+
+#[bench]
+fn tcp_requests_window(b: &mut Bencher) {
+    bench_tcp(b, window());
+}
+
+#[bench]
+fn tcp_notifications_immediate(b: &mut Bencher) {
+    bench_tcp_notifications(b, FlushPolicy::Immediate);
+}
+
+#[bench]
+fn tcp_notifications_batched(b: &mut Bencher) {
+    bench_tcp_notifications(b, batched());
+}
+
+#[bench]
+fn tcp_notifications_window(b: &mut Bencher) {
+    bench_tcp_notifications(b, window());
+}
@@ -16,7 +16,7 @@ fn main() {
 
 #[cfg(feature = "with-serde")]
 mod imp {
-    use msgpack_rpc::{Endpoint, Client, Handler};
+    use msgpack_rpc::{Endpoint, Client, Handler, RequestContext};
     use msgpack_rpc::io::{StdioStream, ChildProcessStream};
 
     use std::env;
@@ -40,7 +40,13 @@ mod imp {
         type RequestFuture = BoxFuture<Value, Value>;
         type NotifyFuture = FutureResult<(), ()>;
 
-        fn handle_request(&self, method: &str, params: Value, _: &Client) -> Self::RequestFuture {
+        fn handle_request(
+            &self,
+            method: &str,
+            params: Value,
+            _: &Client,
+            _: &RequestContext,
+        ) -> Self::RequestFuture {
             match method {
                 "0:function:the_answer" => ok(42u64.into()).boxed(),
                 "0:function:delay" => {
@@ -58,7 +64,13 @@ mod imp {
             }
         }
 
-        fn handle_notification(&self, _: &str, _: Value, _: &Client) -> Self::NotifyFuture {
+        fn handle_notification(
+            &self,
+            _: &str,
+            _: Value,
+            _: &Client,
+            _: &RequestContext,
+        ) -> Self::NotifyFuture {
             ok(())
         }
     }
@@ -68,7 +80,7 @@ mod imp {
         let handle = core.handle();
 
         // Create an asynchronous I/O of standard input/standard output.
-        let stdio = StdioStream::new(4);
+        let stdio = StdioStream::from_handle(&handle);
 
         // Launch a RPC endpoint with given service handlers.
         let endpoint = Endpoint::from_io(&handle, stdio);
@@ -6,12 +6,12 @@ extern crate structopt;
 extern crate structopt_derive;
 
 use futures::Future;
-use tokio_core::net::TcpStream;
 use tokio_core::reactor::Core;
 use msgpack_rpc::{Endpoint, Value};
 use structopt::StructOpt;
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 #[derive(StructOpt)]
 struct Options {
@@ -26,6 +26,10 @@ struct Options {
 
     #[structopt(short = "n", long = "notify")]
     notify: bool,
+
+    #[structopt(short = "t", long = "timeout", help = "Timeout of connection in seconds",
+                default_value = "10")]
+    timeout: u64,
 }
 
 fn main() {
@@ -34,12 +38,14 @@ fn main() {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let task = TcpStream::connect(&opt.addr, &handle).and_then(
-        move |stream| -> Box<Future<Item = (), Error = io::Error>> {
+    let connect = Endpoint::connect_tcp(&handle, &opt.addr)
+        .timeout(Duration::from_secs(opt.timeout));
+    let task = connect.and_then(
+        move |endpoint| -> Box<Future<Item = (), Error = io::Error>> {
             let method = opt.method.as_str();
             let args = Value::Array(opt.args.into_iter().map(Into::into).collect::<Vec<Value>>());
 
-            let client = Endpoint::from_io(&handle, stream).into_client();
+            let client = endpoint.into_client();
             if opt.notify {
                 Box::new(client.notify(method, args))
             } else {
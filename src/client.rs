@@ -1,8 +1,11 @@
 use std::io;
-use futures::{Future, Stream, Sink, Poll, Async, StartSend};
-use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::sync::mpsc::{Sender, Receiver};
 use futures::sync::oneshot;
-use tokio_core::reactor::{Handle, Remote};
+use futures::task::{self, Task};
+use tokio_core::reactor::Handle;
 use tokio_proto::BindClient;
 use tokio_proto::multiplex::ClientService;
 use tokio_service::Service;
@@ -13,8 +16,8 @@ use super::util::io_error;
 
 
 struct ClientTransport {
-    stream: UnboundedReceiver<(u64, message::Response)>,
-    sink: UnboundedSender<(u64, message::Request)>,
+    stream: Receiver<(u64, message::Response)>,
+    sink: Sender<(u64, message::Request)>,
 }
 
 impl Stream for ClientTransport {
@@ -73,36 +76,132 @@ impl Future for Response {
 }
 
 
-pub struct Ack(oneshot::Receiver<()>);
+type OutgoingNotification = (message::Notification, oneshot::Sender<()>);
+
+/// The sender of notifications, shared by all clones of a client.
+///
+/// The channel parks only the last task which has failed to send, so the other tasks waiting
+/// for its capacity are notified after each successful send.
+struct Notifier {
+    tx: Mutex<Sender<OutgoingNotification>>,
+    waiters: Mutex<Vec<Task>>,
+}
+
+impl Notifier {
+    fn start_send(&self, item: OutgoingNotification) -> StartSend<OutgoingNotification, io::Error> {
+        let res = self.tx.lock().unwrap().start_send(item);
+        match res {
+            Ok(AsyncSink::Ready) => {
+                self.wake();
+                Ok(AsyncSink::Ready)
+            }
+            Ok(AsyncSink::NotReady(item)) => {
+                self.waiters.lock().unwrap().push(task::current());
+                Ok(AsyncSink::NotReady(item))
+            }
+            Err(_) => Err(io_error("the connection has been closed")),
+        }
+    }
+
+    fn wake(&self) {
+        let tasks = mem::take(&mut *self.waiters.lock().unwrap());
+        for task in tasks {
+            task.notify();
+        }
+    }
+}
+
+
+/// The return type of `Client::notify()`, which is resolved when the notification is written.
+///
+/// When the outgoing queue of notifications is full, the notification is queued only when
+/// this future is polled and the queue has room, which propagates the backpressure to the
+/// caller. If the future is dropped before that, the notification is queued by a background
+/// task instead.
+pub struct Ack {
+    pending: Option<OutgoingNotification>,
+    notifier: Arc<Notifier>,
+    rx_done: oneshot::Receiver<()>,
+    handle: Handle,
+}
 
 impl Future for Ack {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll().map_err(
+        if let Some(item) = self.pending.take() {
+            if let AsyncSink::NotReady(item) = self.notifier.start_send(item)? {
+                self.pending = Some(item);
+                return Ok(Async::NotReady);
+            }
+        }
+        self.rx_done.poll().map_err(
             |e| io_error(format!("Ack::poll(): {:?}", e)),
         )
     }
 }
 
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if let Some(item) = self.pending.take() {
+            self.handle.spawn(Deliver {
+                method: item.0.method.clone(),
+                pending: Some(item),
+                notifier: self.notifier.clone(),
+            });
+        }
+    }
+}
+
+
+/// A background task which queues a notification whose `Ack` has been dropped.
+struct Deliver {
+    method: String,
+    pending: Option<OutgoingNotification>,
+    notifier: Arc<Notifier>,
+}
+
+impl Future for Deliver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let item = match self.pending.take() {
+            Some(item) => item,
+            None => return Ok(Async::Ready(())),
+        };
+        match self.notifier.start_send(item) {
+            Ok(AsyncSink::Ready) => Ok(Async::Ready(())),
+            Ok(AsyncSink::NotReady(item)) => {
+                self.pending = Some(item);
+                Ok(Async::NotReady)
+            }
+            Err(err) => {
+                warn!("discarded the notification '{}': {}", self.method, err);
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
 
 
 /// A client of Msgpack-RPC
 #[derive(Clone)]
 pub struct Client {
     inner: ClientService<ClientTransport, ClientProto>,
-    tx_not: UnboundedSender<(message::Notification, oneshot::Sender<()>)>,
-    handle: Remote,
+    notifier: Arc<Notifier>,
+    handle: Handle,
 }
 
 impl Client {
     /// Create a new `Client` with background task spawned on an event loop of `handle`.
     pub fn new(
         handle: &Handle,
-        tx_req: UnboundedSender<(u64, message::Request)>,
-        rx_res: UnboundedReceiver<(u64, message::Response)>,
-        tx_not: UnboundedSender<(message::Notification, oneshot::Sender<()>)>,
+        tx_req: Sender<(u64, message::Request)>,
+        rx_res: Receiver<(u64, message::Response)>,
+        tx_not: Sender<(message::Notification, oneshot::Sender<()>)>,
     ) -> Self {
         let transport = ClientTransport {
             stream: rx_res,
@@ -112,8 +211,11 @@ impl Client {
         let inner = ClientProto.bind_client(handle, transport);
         Client {
             inner,
-            tx_not,
-            handle: handle.remote().clone(),
+            notifier: Arc::new(Notifier {
+                tx: Mutex::new(tx_not),
+                waiters: Mutex::new(Vec::new()),
+            }),
+            handle: handle.clone(),
         }
     }
 
@@ -123,15 +225,21 @@ impl Client {
     }
 
     /// Send a notification message to the server.
+    ///
+    /// The notification is queued immediately if the outgoing queue has room. Otherwise, it
+    /// is queued when the returned `Ack` is polled, or in the background if it is dropped.
     pub fn notify<S: Into<String>, P: Into<Value>>(&self, method: S, params: P) -> Ack {
         let not = message::Notification::new(method, params);
-
-        let tx = self.tx_not.clone();
         let (tx_done, rx_done) = oneshot::channel();
-        self.handle.spawn(|_| {
-            tx.send((not, tx_done)).map(|_| ()).map_err(|_| ())
-        });
-
-        Ack { 0: rx_done }
+        let pending = match self.notifier.tx.lock().unwrap().try_send((not, tx_done)) {
+            Ok(()) => None,
+            Err(err) => Some(err.into_inner()),
+        };
+        Ack {
+            pending,
+            notifier: self.notifier.clone(),
+            rx_done,
+            handle: self.handle.clone(),
+        }
     }
 }
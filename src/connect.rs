@@ -0,0 +1,127 @@
+use std::ffi::OsStr;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+use futures::{Future, Poll};
+use futures::future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+use super::endpoint::{Endpoint, EndpointBuilder};
+use super::io::ChildProcessStream;
+#[cfg(unix)]
+use super::io::unix;
+use super::util::Deadline;
+
+
+/// A future of an `Endpoint` which is connecting to the peer.
+///
+/// Errors are reported as `io::Error`. When the timeout set by `Connect::timeout()` has
+/// elapsed, the future fails with `io::ErrorKind::TimedOut`.
+pub struct Connect {
+    inner: Deadline<Box<Future<Item = Endpoint, Error = io::Error>>>,
+}
+
+impl Connect {
+    pub(crate) fn new<F>(handle: &Handle, inner: F) -> Self
+    where
+        F: Future<Item = Endpoint, Error = io::Error> + 'static,
+    {
+        let inner: Box<Future<Item = Endpoint, Error = io::Error>> = Box::new(inner);
+        Connect {
+            inner: Deadline::new(inner, handle, "timed out while connecting to the peer"),
+        }
+    }
+
+    /// Set the maximum duration to wait for the connection, measured from the first poll.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.inner = self.inner.timeout(timeout);
+        self
+    }
+}
+
+impl Future for Connect {
+    type Item = Endpoint;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.inner.poll()
+    }
+}
+
+
+impl EndpointBuilder {
+    /// Connect to the peer over TCP, and create a RPC endpoint.
+    pub fn connect_tcp(&self, handle: &Handle, addr: &SocketAddr) -> Connect {
+        let builder = self.clone();
+        let h = handle.clone();
+        let addr = *addr;
+        Connect::new(
+            handle,
+            TcpStream::connect(&addr, handle).map(move |sock| {
+                builder.from_io(&h, sock).with_peer_addr(addr)
+            }),
+        )
+    }
+
+    /// Connect to the peer over a Unix domain socket, and create a RPC endpoint.
+    ///
+    /// The credentials of the peer are available as `PeerCredentials` in the extensions of
+    /// the connection. Connecting to a Unix domain socket completes or fails immediately
+    /// (e.g. when the backlog of the listener is full), so `Connect::timeout()` has no effect.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(&self, handle: &Handle, path: P) -> Connect {
+        Connect::new(handle, future::result(unix::connect(self, handle, path)))
+    }
+
+    /// Spawn a child process, and create a RPC endpoint over its standard input/output.
+    ///
+    /// The process is owned by the I/O of the connection, and is killed once the connection
+    /// has stopped both reading and writing. Writing stops when the endpoint and its clients
+    /// are dropped, which closes the standard input of the process; reading stops when the
+    /// process closes its standard output. Use `ChildProcessStream::take_process()` with
+    /// `from_io()` to wait for the exit of the process, or to keep it running.
+    pub fn spawn_process<S, I, A>(&self, handle: &Handle, program: S, args: I) -> Connect
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        let endpoint = ChildProcessStream::launch(handle, program, args)
+            .map(|child| self.from_io(handle, child));
+        Connect::new(handle, future::result(endpoint))
+    }
+}
+
+
+impl Endpoint {
+    /// Connect to the peer over TCP, with the default configuration.
+    ///
+    /// ```ignore
+    /// let task = Endpoint::connect_tcp(&handle, &addr)
+    ///     .timeout(Duration::from_secs(5))
+    ///     .and_then(|endpoint| endpoint.into_client().request("hello", vec![]));
+    /// ```
+    pub fn connect_tcp(handle: &Handle, addr: &SocketAddr) -> Connect {
+        EndpointBuilder::new().connect_tcp(handle, addr)
+    }
+
+    /// Connect to the peer over a Unix domain socket, with the default configuration.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(handle: &Handle, path: P) -> Connect {
+        EndpointBuilder::new().connect_unix(handle, path)
+    }
+
+    /// Spawn a child process and communicate over its standard input/output, with the default
+    /// configuration.
+    pub fn spawn_process<S, I, A>(handle: &Handle, program: S, args: I) -> Connect
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        EndpointBuilder::new().spawn_process(handle, program, args)
+    }
+}
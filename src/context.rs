@@ -0,0 +1,367 @@
+use std::any::{Any, TypeId};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Instant;
+use futures::{Future, Poll, Async};
+
+use super::util::Waiters;
+
+static NEXT_CONNECTION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+/// A type map to store arbitrary values, keyed by their types.
+#[derive(Default)]
+pub struct Extensions(HashMap<TypeId, Box<Any>>);
+
+impl Extensions {
+    /// Insert a value, and return the previous value of the same type if exists.
+    pub fn insert<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.0.insert(TypeId::of::<T>(), Box::new(value)).and_then(
+            |prev| prev.downcast().ok().map(|prev| *prev),
+        )
+    }
+
+    /// Return the reference of the value of type `T`.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>()).and_then(
+            |value| value.downcast_ref(),
+        )
+    }
+
+    /// Return the mutable reference of the value of type `T`.
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.0.get_mut(&TypeId::of::<T>()).and_then(
+            |value| value.downcast_mut(),
+        )
+    }
+
+    /// Remove the value of type `T`, and return it.
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.0.remove(&TypeId::of::<T>()).and_then(|value| {
+            value.downcast().ok().map(|value| *value)
+        })
+    }
+}
+
+
+struct ConnectionInner {
+    id: u64,
+    peer_addr: Cell<Option<SocketAddr>>,
+    extensions: RefCell<Extensions>,
+    closed: Cell<bool>,
+    aborted: Cell<bool>,
+    error: RefCell<Option<io::Error>>,
+    in_flight: Cell<usize>,
+    queued: Cell<usize>,
+    notifying: Cell<usize>,
+    unwritten: Cell<usize>,
+    writer_done: Cell<bool>,
+    last_activity: Cell<Instant>,
+    waiters: Waiters,
+    abort_waiters: Waiters,
+}
+
+/// A handle of the connection associated with an endpoint.
+///
+/// All requests/notifications arrived from the same peer share the connection, so it can be
+/// used to keep per-connection state (e.g. sessions or authorization) in its extensions.
+#[derive(Clone)]
+pub struct Connection(Rc<ConnectionInner>);
+
+impl Connection {
+    pub(crate) fn new() -> Self {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst) as u64;
+        Connection(Rc::new(ConnectionInner {
+            id,
+            peer_addr: Cell::new(None),
+            extensions: Default::default(),
+            closed: Cell::new(false),
+            aborted: Cell::new(false),
+            error: RefCell::new(None),
+            in_flight: Cell::new(0),
+            queued: Cell::new(0),
+            notifying: Cell::new(0),
+            unwritten: Cell::new(0),
+            writer_done: Cell::new(false),
+            last_activity: Cell::new(Instant::now()),
+            waiters: Default::default(),
+            abort_waiters: Default::default(),
+        }))
+    }
+
+    /// Return the ID of the connection, which is unique in the process.
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    /// Return the address of the peer, if the connection is over TCP.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.peer_addr.get()
+    }
+
+    pub(crate) fn set_peer_addr(&self, addr: SocketAddr) {
+        self.0.peer_addr.set(Some(addr));
+    }
+
+    /// Return the reference of the extensions map of the connection.
+    pub fn extensions(&self) -> Ref<Extensions> {
+        self.0.extensions.borrow()
+    }
+
+    /// Return the mutable reference of the extensions map of the connection.
+    pub fn extensions_mut(&self) -> RefMut<Extensions> {
+        self.0.extensions.borrow_mut()
+    }
+
+    /// Return whether the connection has been closed by the peer.
+    pub fn is_closed(&self) -> bool {
+        self.0.closed.get()
+    }
+
+    /// Return a future which will be resolved when the connection is closed.
+    pub fn closed(&self) -> Cancellation {
+        Cancellation(self.clone())
+    }
+
+    /// Return the error which caused the connection to be closed, if any.
+    pub fn error(&self) -> Ref<Option<io::Error>> {
+        self.0.error.borrow()
+    }
+
+    /// Return the number of requests from the peer which have not been responded yet.
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight.get()
+    }
+
+    /// Return the time of the last message from the peer, the last completed
+    /// request/notification, or the last write to the peer.
+    pub fn last_activity(&self) -> Instant {
+        self.0.last_activity.get()
+    }
+
+    pub(crate) fn touch(&self) {
+        self.0.last_activity.set(Instant::now());
+    }
+
+    /// Count a request or a notification which has arrived but is not handled yet.
+    pub(crate) fn enqueue(&self) {
+        self.0.queued.set(self.0.queued.get() + 1);
+    }
+
+    /// Mark the start of a request, which is completed when the returned guard is dropped.
+    pub(crate) fn begin_request(&self) -> RequestGuard {
+        self.0.queued.set(self.0.queued.get().saturating_sub(1));
+        self.0.in_flight.set(self.0.in_flight.get() + 1);
+        self.0.waiters.notify_all();
+        RequestGuard(self.clone())
+    }
+
+    /// Mark the start of a notification, which is completed when the returned guard is dropped.
+    pub(crate) fn begin_notification(&self) -> NotificationGuard {
+        self.0.queued.set(self.0.queued.get().saturating_sub(1));
+        self.0.notifying.set(self.0.notifying.get() + 1);
+        NotificationGuard(self.clone())
+    }
+
+    /// Mark responses as written to the I/O.
+    pub(crate) fn write_responses(&self, n: usize) {
+        if n > 0 {
+            self.0.unwritten.set(self.0.unwritten.get().saturating_sub(n));
+            self.0.waiters.notify_all();
+        }
+    }
+
+    /// Mark that no more messages will be written to the I/O.
+    pub(crate) fn finish_writer(&self) {
+        self.0.writer_done.set(true);
+        self.0.waiters.notify_all();
+    }
+
+    /// Return whether all of the requests and notifications from the peer have been handled,
+    /// and their responses have been written to the I/O.
+    pub(crate) fn is_settled(&self) -> bool {
+        let inner = &self.0;
+        inner.queued.get() == 0 && inner.in_flight.get() == 0 && inner.notifying.get() == 0 &&
+            (inner.unwritten.get() == 0 || inner.writer_done.get())
+    }
+
+    /// Register the current task, to be notified when the state of the connection is changed.
+    pub(crate) fn watch(&self) {
+        self.0.waiters.register();
+    }
+
+    pub(crate) fn close(&self, error: Option<io::Error>) {
+        if self.0.closed.get() {
+            return;
+        }
+        self.0.closed.set(true);
+        *self.0.error.borrow_mut() = error;
+        self.0.waiters.notify_all();
+    }
+
+    /// Close the connection immediately, dropping the underlying I/O and any unsent messages.
+    pub(crate) fn abort(&self, reason: &str) {
+        if self.0.aborted.get() {
+            return;
+        }
+        self.0.aborted.set(true);
+        self.close(Some(io::Error::new(io::ErrorKind::ConnectionAborted, reason)));
+        self.0.abort_waiters.notify_all();
+    }
+
+    /// Wrap a task driving the I/O of the connection, so that it is dropped on abort.
+    pub(crate) fn abortable<F: Future<Item = ()>>(&self, inner: F) -> Abortable<F> {
+        Abortable {
+            inner,
+            connection: self.clone(),
+        }
+    }
+}
+
+
+/// A task which is stopped when the connection is aborted.
+pub(crate) struct Abortable<F> {
+    inner: F,
+    connection: Connection,
+}
+
+impl<F: Future<Item = ()>> Future for Abortable<F> {
+    type Item = ();
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &(self.connection).0;
+        if inner.aborted.get() {
+            return Ok(Async::Ready(()));
+        }
+        inner.abort_waiters.register();
+        self.inner.poll()
+    }
+}
+
+
+pub(crate) struct RequestGuard(Connection);
+
+impl RequestGuard {
+    /// Count the response of the request, until it is written to the I/O.
+    pub(crate) fn respond(&self) {
+        let inner = &(self.0).0;
+        inner.unwritten.set(inner.unwritten.get() + 1);
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let inner = &(self.0).0;
+        inner.in_flight.set(inner.in_flight.get() - 1);
+        inner.last_activity.set(Instant::now());
+        inner.waiters.notify_all();
+    }
+}
+
+
+pub(crate) struct NotificationGuard(Connection);
+
+impl Drop for NotificationGuard {
+    fn drop(&mut self) {
+        let inner = &(self.0).0;
+        inner.notifying.set(inner.notifying.get() - 1);
+        inner.last_activity.set(Instant::now());
+        inner.waiters.notify_all();
+    }
+}
+
+
+/// A future which will be resolved when the request should be aborted.
+pub struct Cancellation(Connection);
+
+impl Future for Cancellation {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if (self.0).0.closed.get() {
+            Ok(Async::Ready(()))
+        } else {
+            (self.0).0.waiters.register();
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+
+/// The context of an incoming request/notification, passed to handlers.
+pub struct RequestContext {
+    msgid: Option<u64>,
+    arrival_time: Instant,
+    deadline: Option<Instant>,
+    connection: Connection,
+}
+
+impl RequestContext {
+    pub(crate) fn new(msgid: Option<u64>, deadline: Option<Instant>, connection: Connection) -> Self {
+        RequestContext {
+            msgid,
+            arrival_time: Instant::now(),
+            deadline,
+            connection,
+        }
+    }
+
+    /// Return the ID of the request, or `None` if it is a notification.
+    pub fn msgid(&self) -> Option<u64> {
+        self.msgid
+    }
+
+    /// Return the connection which the request arrived from.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Return the ID of the connection.
+    pub fn connection_id(&self) -> u64 {
+        self.connection.id()
+    }
+
+    /// Return the address of the peer, if the connection is over TCP.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.connection.peer_addr()
+    }
+
+    /// Return the time when the request arrived.
+    pub fn arrival_time(&self) -> Instant {
+        self.arrival_time
+    }
+
+    /// Return the deadline of the request, configured by `Endpoint::request_timeout()`.
+    ///
+    /// When the deadline has passed, the endpoint responds with an error and drops the future
+    /// returned from the handler.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Return the reference of the per-connection extensions map.
+    pub fn extensions(&self) -> Ref<Extensions> {
+        self.connection.extensions()
+    }
+
+    /// Return the mutable reference of the per-connection extensions map.
+    pub fn extensions_mut(&self) -> RefMut<Extensions> {
+        self.connection.extensions_mut()
+    }
+
+    /// Return whether the request should be aborted, since the connection has been closed.
+    pub fn is_cancelled(&self) -> bool {
+        self.connection.is_closed()
+    }
+
+    /// Return a future which will be resolved when the request should be aborted.
+    pub fn cancellation(&self) -> Cancellation {
+        self.connection.closed()
+    }
+}
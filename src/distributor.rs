@@ -1,24 +1,35 @@
 use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
 use futures::{Future, Stream, Sink, Poll, Async, AsyncSink};
-use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use futures::sync::mpsc::{Sender, Receiver};
 use futures::sync::oneshot;
-use super::message::{EncoderMessage, DecoderMessage, Request, Response, Notification};
+use tokio_core::reactor::{Handle, Timeout};
+use super::context::Connection;
+use super::message::{Buffered, EncoderMessage, DecoderMessage, Request, Response, Notification};
+use super::tap::{Direction, Frame, Tap};
+use super::util::io_error;
 
 
-pub(crate) struct Demux<T: Stream<Item = DecoderMessage>> {
+pub(crate) struct Demux<T: Stream<Item = DecoderMessage, Error = io::Error>> {
     stream: Option<T>,
     buffer: Option<DecoderMessage>,
-    tx0: UnboundedSender<(u64, Request)>,
-    tx1: UnboundedSender<(u64, Response)>,
-    tx2: UnboundedSender<Notification>,
+    tx0: Sender<(u64, Request)>,
+    tx1: Sender<(u64, Response)>,
+    tx2: Sender<Notification>,
+    tap: Option<Rc<Tap>>,
+    connection_id: u64,
 }
 
-impl<T: Stream<Item = DecoderMessage>> Demux<T> {
+impl<T: Stream<Item = DecoderMessage, Error = io::Error>> Demux<T> {
     pub(crate) fn new(
         stream: T,
-        tx0: UnboundedSender<(u64, Request)>,
-        tx1: UnboundedSender<(u64, Response)>,
-        tx2: UnboundedSender<Notification>,
+        tx0: Sender<(u64, Request)>,
+        tx1: Sender<(u64, Response)>,
+        tx2: Sender<Notification>,
+        tap: Option<Rc<Tap>>,
+        connection_id: u64,
     ) -> Self {
         Demux {
             stream: Some(stream),
@@ -26,6 +37,8 @@ impl<T: Stream<Item = DecoderMessage>> Demux<T> {
             tx0,
             tx1,
             tx2,
+            tap,
+            connection_id,
         }
     }
 
@@ -33,11 +46,11 @@ impl<T: Stream<Item = DecoderMessage>> Demux<T> {
         self.stream.as_mut().take().unwrap()
     }
 
-    fn try_start_send(&mut self, item: DecoderMessage) -> Poll<(), ()> {
+    fn try_start_send(&mut self, item: DecoderMessage) -> Poll<(), io::Error> {
         match item {
             DecoderMessage::Request(id, req) => {
                 if let AsyncSink::NotReady((id, req)) =
-                    self.tx0.start_send((id, req)).map_err(|_| ())?
+                    self.tx0.start_send((id, req)).map_err(|_| closed("requests"))?
                 {
                     self.buffer = Some(DecoderMessage::Request(id, req));
                     return Ok(Async::NotReady);
@@ -45,14 +58,16 @@ impl<T: Stream<Item = DecoderMessage>> Demux<T> {
             }
             DecoderMessage::Response(id, res) => {
                 if let AsyncSink::NotReady((id, res)) =
-                    self.tx1.start_send((id, res)).map_err(|_| ())?
+                    self.tx1.start_send((id, res)).map_err(|_| closed("responses"))?
                 {
                     self.buffer = Some(DecoderMessage::Response(id, res));
                     return Ok(Async::NotReady);
                 }
             }
             DecoderMessage::Notification(not) => {
-                if let AsyncSink::NotReady(not) = self.tx2.start_send(not).map_err(|_| ())? {
+                if let AsyncSink::NotReady(not) =
+                    self.tx2.start_send(not).map_err(|_| closed("notifications"))?
+                {
                     self.buffer = Some(DecoderMessage::Notification(not));
                     return Ok(Async::NotReady);
                 }
@@ -62,29 +77,36 @@ impl<T: Stream<Item = DecoderMessage>> Demux<T> {
     }
 }
 
-impl<T: Stream<Item = DecoderMessage>> Future for Demux<T> {
+impl<T: Stream<Item = DecoderMessage, Error = io::Error>> Future for Demux<T> {
     type Item = ();
-    type Error = ();
+    type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<(), ()> {
+    fn poll(&mut self) -> Poll<(), io::Error> {
         if let Some(item) = self.buffer.take() {
             try_ready!(self.try_start_send(item))
         }
 
         loop {
-            match self.stream_mut().poll().map_err(|_| ())? {
-                Async::Ready(Some(item)) => try_ready!(self.try_start_send(item)),
+            match self.stream_mut().poll()? {
+                Async::Ready(Some(item)) => {
+                    if let Some(ref tap) = self.tap {
+                        tap.tap(&Frame::new(Direction::Inbound, self.connection_id, &item));
+                    }
+                    try_ready!(self.try_start_send(item))
+                }
                 Async::Ready(None) => {
-                    try_ready!(self.tx0.close().map_err(|_| ()));
-                    try_ready!(self.tx1.close().map_err(|_| ()));
-                    try_ready!(self.tx2.close().map_err(|_| ()));
+                    try_ready!(self.tx0.close().map_err(|_| closed("requests")));
+                    try_ready!(self.tx1.close().map_err(|_| closed("responses")));
+                    try_ready!(self.tx2.close().map_err(|_| closed("notifications")));
                     self.stream = None;
                     return Ok(Async::Ready(()));
                 }
                 Async::NotReady => {
-                    try_ready!(self.tx0.poll_complete().map_err(|_| ()));
-                    try_ready!(self.tx1.poll_complete().map_err(|_| ()));
-                    try_ready!(self.tx2.poll_complete().map_err(|_| ()));
+                    try_ready!(self.tx0.poll_complete().map_err(|_| closed("requests")));
+                    try_ready!(self.tx1.poll_complete().map_err(|_| closed("responses")));
+                    try_ready!(self.tx2.poll_complete().map_err(
+                        |_| closed("notifications"),
+                    ));
                     return Ok(Async::NotReady);
                 }
             }
@@ -92,98 +114,272 @@ impl<T: Stream<Item = DecoderMessage>> Future for Demux<T> {
     }
 }
 
+fn closed(lane: &str) -> io::Error {
+    io_error(format!("the receiver of incoming {} has been dropped", lane))
+}
+
+
+
+/// The kinds of outgoing messages, which are multiplexed into the I/O.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// Requests sent from the client
+    Request,
+    /// Responses to the requests from the peer
+    Response,
+    /// Notifications sent from the client
+    Notification,
+}
+
+impl Lane {
+    fn index(&self) -> usize {
+        match *self {
+            Lane::Request => 0,
+            Lane::Response => 1,
+            Lane::Notification => 2,
+        }
+    }
+}
+
+/// The order and weights of lanes, used by `Mux` to pick outgoing messages.
+///
+/// In each round, `Mux` takes at most `weight` messages from each lane, in order of priority.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Schedule([(Lane, usize); 3]);
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule([(Lane::Request, 1), (Lane::Response, 1), (Lane::Notification, 1)])
+    }
+}
 
+impl Schedule {
+    pub(crate) fn set_priority(&mut self, order: [Lane; 3]) {
+        assert!(
+            order[0] != order[1] && order[1] != order[2] && order[2] != order[0],
+            "each lane must appear exactly once"
+        );
+        let weights = self.weights();
+        for (slot, &lane) in self.0.iter_mut().zip(order.iter()) {
+            *slot = (lane, weights[lane.index()]);
+        }
+    }
+
+    pub(crate) fn set_weight(&mut self, lane: Lane, weight: usize) {
+        assert!(weight > 0, "the weight of a lane must be positive");
+        for slot in self.0.iter_mut().filter(|slot| slot.0 == lane) {
+            slot.1 = weight;
+        }
+    }
+
+    fn weights(&self) -> [usize; 3] {
+        let mut weights = [0; 3];
+        for &(lane, weight) in self.0.iter() {
+            weights[lane.index()] = weight;
+        }
+        weights
+    }
+}
+
+
+/// The policy to flush outgoing messages written to the I/O.
+#[derive(Debug, Clone, Copy)]
+pub enum FlushPolicy {
+    /// Flush whenever no more outgoing messages are ready to be sent (the default).
+    Immediate,
+
+    /// Flush when the buffered messages reach `bytes` bytes, or when `max_delay` has elapsed
+    /// since the messages were buffered.
+    Batched {
+        /// The threshold of buffered bytes
+        bytes: usize,
+        /// The maximum delay of buffered messages
+        max_delay: Duration,
+    },
+
+    /// Flush at most once per given window, like Nagle's algorithm.
+    Window(Duration),
+}
 
-pub(crate) struct Mux<U: Sink<SinkItem = EncoderMessage>> {
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy::Immediate
+    }
+}
+
+
+/// The configuration of `Mux`, taken from the builder of the endpoint.
+pub(crate) struct MuxOptions {
+    pub schedule: Schedule,
+    pub flush: FlushPolicy,
+    pub tap: Option<Rc<Tap>>,
+}
+
+
+pub(crate) struct Mux<U: Sink<SinkItem = EncoderMessage, SinkError = io::Error> + Buffered> {
     sink: U,
+    connection: Connection,
+    unflushed: usize,
     buffer: VecDeque<EncoderMessage>,
-    rx0: UnboundedReceiver<(u64, Request)>,
-    rx1: UnboundedReceiver<(u64, Response)>,
-    rx2: UnboundedReceiver<(Notification, oneshot::Sender<()>)>,
+    schedule: Schedule,
+    done: [bool; 3],
+    flush: FlushPolicy,
+    pending: bool,
+    timer: Option<Timeout>,
+    handle: Handle,
+    tap: Option<Rc<Tap>>,
+    rx0: Receiver<(u64, Request)>,
+    rx1: Receiver<(u64, Response)>,
+    rx2: Receiver<(Notification, oneshot::Sender<()>)>,
 }
 
-impl<U: Sink<SinkItem = EncoderMessage>> Mux<U> {
+impl<U: Sink<SinkItem = EncoderMessage, SinkError = io::Error> + Buffered> Mux<U> {
     pub(crate) fn new(
         sink: U,
-        rx0: UnboundedReceiver<(u64, Request)>,
-        rx1: UnboundedReceiver<(u64, Response)>,
-        rx2: UnboundedReceiver<(Notification, oneshot::Sender<()>)>,
+        connection: Connection,
+        options: MuxOptions,
+        handle: &Handle,
+        rx0: Receiver<(u64, Request)>,
+        rx1: Receiver<(u64, Response)>,
+        rx2: Receiver<(Notification, oneshot::Sender<()>)>,
     ) -> Self {
         Mux {
             sink,
+            connection,
+            unflushed: 0,
             buffer: Default::default(),
+            schedule: options.schedule,
+            done: [false; 3],
+            flush: options.flush,
+            pending: false,
+            timer: None,
+            handle: handle.clone(),
+            tap: options.tap,
             rx0,
             rx1,
             rx2,
         }
     }
 
-    fn try_recv(&mut self) -> Poll<Option<Vec<EncoderMessage>>, ()> {
-        let mut buf = Vec::with_capacity(3);
-        let done0 = match self.rx0.poll()? {
-            Async::Ready(Some((id, req))) => {
-                buf.push(EncoderMessage::Request(id, req));
-                false
+    fn recv_lane(&mut self, lane: Lane) -> Poll<Option<EncoderMessage>, ()> {
+        let msg = match lane {
+            Lane::Request => {
+                try_ready!(self.rx0.poll()).map(|(id, req)| EncoderMessage::Request(id, req))
             }
-            Async::Ready(None) => true,
-            Async::NotReady => false,
-        };
-        let done1 = match self.rx1.poll()? {
-            Async::Ready(Some((id, res))) => {
-                buf.push(EncoderMessage::Response(id, res));
-                false
+            Lane::Response => {
+                try_ready!(self.rx1.poll()).map(|(id, res)| EncoderMessage::Response(id, res))
             }
-            Async::Ready(None) => true,
-            Async::NotReady => false,
-        };
-        let done2 = match self.rx2.poll()? {
-            Async::Ready(Some((not, sender))) => {
-                buf.push(EncoderMessage::Notification(not, sender));
-                false
+            Lane::Notification => {
+                try_ready!(self.rx2.poll()).map(|(not, sender)| {
+                    EncoderMessage::Notification(not, sender)
+                })
             }
-            Async::Ready(None) => true,
-            Async::NotReady => false,
         };
+        Ok(Async::Ready(msg))
+    }
+
+    fn try_recv(&mut self) -> Poll<Option<Vec<EncoderMessage>>, ()> {
+        let mut buf = Vec::new();
+        let schedule = self.schedule;
+        for &(lane, weight) in schedule.0.iter() {
+            for _ in 0..weight {
+                if self.done[lane.index()] {
+                    break;
+                }
+                match self.recv_lane(lane)? {
+                    Async::Ready(Some(msg)) => buf.push(msg),
+                    Async::Ready(None) => self.done[lane.index()] = true,
+                    Async::NotReady => break,
+                }
+            }
+        }
 
-        if done0 && done1 && done2 {
+        if self.done.iter().all(|&done| done) {
             Ok(Async::Ready(None))
-        } else if buf.len() > 0 {
+        } else if !buf.is_empty() {
             Ok(Async::Ready(Some(buf)))
         } else {
             Ok(Async::NotReady)
         }
     }
 
-    fn start_send(&mut self) -> Poll<(), ()> {
+    fn start_send(&mut self) -> Poll<(), io::Error> {
         while let Some(item) = self.buffer.pop_front() {
-            if let AsyncSink::NotReady(item) = self.sink.start_send(item).map_err(|_| ())? {
+            let is_response = match item {
+                EncoderMessage::Response(..) => true,
+                _ => false,
+            };
+            if let AsyncSink::NotReady(item) = self.sink.start_send(item)? {
                 self.buffer.push_front(item);
                 return Ok(Async::NotReady);
             }
+            if is_response {
+                self.unflushed += 1;
+            }
+            self.pending = true;
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Return whether the pending messages should be flushed now, according to the policy.
+    fn ready_to_flush(&mut self) -> io::Result<bool> {
+        let (bytes, delay) = match self.flush {
+            FlushPolicy::Immediate => return Ok(true),
+            FlushPolicy::Batched { bytes, max_delay } => (bytes, max_delay),
+            FlushPolicy::Window(window) => (usize::MAX, window),
+        };
+        if self.sink.buffered() >= bytes {
+            return Ok(true);
+        }
+        if self.timer.is_none() {
+            self.timer = Some(Timeout::new(delay, &self.handle)?);
+        }
+        match self.timer {
+            Some(ref mut timer) => Ok(timer.poll()?.is_ready()),
+            None => unreachable!(),
+        }
+    }
+
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        if self.pending && self.ready_to_flush()? {
+            try_ready!(self.sink.poll_complete());
+            self.pending = false;
+            self.timer = None;
+            self.connection.touch();
+            self.connection.write_responses(self.unflushed);
+            self.unflushed = 0;
         }
         Ok(Async::Ready(()))
     }
 }
 
-impl<U: Sink<SinkItem = EncoderMessage>> Future for Mux<U> {
+impl<U: Sink<SinkItem = EncoderMessage, SinkError = io::Error> + Buffered> Future for Mux<U> {
     type Item = ();
-    type Error = ();
+    type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<(), ()> {
+    fn poll(&mut self) -> Poll<(), io::Error> {
         loop {
             try_ready!(self.start_send());
-            debug_assert!(self.buffer.len() == 0);
+            debug_assert!(self.buffer.is_empty());
 
-            match self.try_recv()? {
+            match self.try_recv().map_err(
+                |_| io_error("the outgoing channels are broken"),
+            )? {
                 Async::Ready(Some(buf)) => {
+                    if let Some(ref tap) = self.tap {
+                        let id = self.connection.id();
+                        for item in &buf {
+                            tap.tap(&Frame::new(Direction::Outbound, id, item));
+                        }
+                    }
                     self.buffer.extend(buf);
                 }
                 Async::Ready(None) => {
-                    try_ready!(self.sink.close().map_err(|_| ()));
+                    try_ready!(self.sink.close());
                     return Ok(Async::Ready(()));
                 }
                 Async::NotReady => {
-                    try_ready!(self.sink.poll_complete().map_err(|_| ()));
+                    try_ready!(self.flush());
                     return Ok(Async::NotReady);
                 }
             }
@@ -1,36 +1,58 @@
+use std::any::Any;
+use std::fmt;
 use std::io;
-use std::sync::Arc;
-use futures::{Future, Stream, Sink, Poll, StartSend};
-use futures::future::Then;
-use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
-use tokio_core::reactor::Handle;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use futures::{Future, Stream, Sink, Poll, Async, StartSend};
+use futures::future::CatchUnwind;
+use futures::sync::mpsc::{self, Sender, Receiver};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::codec::{FramedRead, FramedWrite};
+use tokio_io::codec::FramedRead;
 use tokio_proto::BindServer;
 use tokio_service::Service;
 use rmpv::Value;
 
 use super::Handler;
 use super::client::Client;
-use super::distributor::{Demux, Mux};
-use super::message::{Codec, Request, Response, Notification};
+use super::context::{Connection, NotificationGuard, RequestContext, RequestGuard};
+use super::distributor::{Demux, Mux, MuxOptions, FlushPolicy, Lane, Schedule};
+use super::error::{self, HandlerError, ErrorHook};
+use super::message::{Buffered, Codec, DecoderMessage, EncoderMessage, MessageWriter, Request,
+                     Response, Notification};
+use super::io::{self as rpc_io, PipeOptions};
+use super::tap::Tap;
 use super::util::io_error;
 
 
 /// A transport consists of a pair of stream/sink.
 struct EndpointTransport {
-    stream: UnboundedReceiver<(u64, Request)>,
-    sink: UnboundedSender<(u64, Response)>,
+    stream: Receiver<(u64, Request)>,
+    sink: Sender<(u64, Response)>,
+    connection: Connection,
+    max_in_flight: Option<usize>,
 }
 
 impl Stream for EndpointTransport {
-    type Item = (u64, Request);
+    type Item = (u64, (u64, Request));
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.stream.poll().map_err(
+        // Stop receiving requests until some of in-flight requests are completed.
+        if let Some(max) = self.max_in_flight {
+            if self.connection.in_flight() >= max {
+                self.connection.watch();
+                return Ok(Async::NotReady);
+            }
+        }
+
+        // The ID is also passed to the service, in order to report it to handlers.
+        let item = try_ready!(self.stream.poll().map_err(
             |_| io_error("EndpontTransport::poll()"),
-        )
+        ));
+        Ok(Async::Ready(item.map(|(id, req)| (id, (id, req)))))
     }
 }
 
@@ -55,7 +77,7 @@ impl Sink for EndpointTransport {
 struct EndpointProto;
 
 impl ::tokio_proto::multiplex::ServerProto<EndpointTransport> for EndpointProto {
-    type Request = Request;
+    type Request = (u64, Request);
     type Response = Response;
     type Transport = EndpointTransport;
     type BindTransport = io::Result<Self::Transport>;
@@ -65,59 +87,377 @@ impl ::tokio_proto::multiplex::ServerProto<EndpointTransport> for EndpointProto
 }
 
 
-struct HandleService<H: Handler>(H, Client);
+struct HandleService<H: Handler> {
+    handler: H,
+    client: Client,
+    connection: Connection,
+    handle: Handle,
+    hook: Option<ErrorHook>,
+    request_timeout: Option<Duration>,
+}
 
 impl<H: Handler> Service for HandleService<H> {
-    type Request = Request;
+    type Request = (u64, Request);
     type Response = Response;
     type Error = io::Error;
-    type Future = Then<
-        H::RequestFuture,
-        Result<Response, io::Error>,
-        fn(Result<Value, Value>) -> Result<Response, io::Error>,
-    >;
+    type Future = HandleRequest<H::RequestFuture>;
 
-    fn call(&self, req: Request) -> Self::Future {
-        self.0
-            .handle_request(&req.method, req.params, &self.1)
-            .then(|res| Ok(Response::from(res)))
+    fn call(&self, (msgid, req): (u64, Request)) -> Self::Future {
+        let Request { method, params } = req;
+        let guard = self.connection.begin_request();
+        let deadline = self.request_timeout.map(|timeout| Instant::now() + timeout);
+        let ctx = RequestContext::new(Some(msgid), deadline, self.connection.clone());
+        let state = match panic::catch_unwind(AssertUnwindSafe(|| {
+            self.handler.handle_request(&method, params, &self.client, &ctx)
+        })) {
+            Ok(future) => State::Running(AssertUnwindSafe(future).catch_unwind()),
+            Err(payload) => State::Panicked(payload),
+        };
+        let timeout = deadline.and_then(|at| match Timeout::new_at(at, &self.handle) {
+            Ok(timeout) => Some(timeout),
+            Err(err) => {
+                warn!("failed to set the timeout of the request `{}`: {}", method, err);
+                None
+            }
+        });
+        HandleRequest {
+            state,
+            timeout,
+            guard,
+            msgid,
+            method,
+            hook: self.hook.clone(),
+        }
+    }
+}
+
+impl<H: Handler> Drop for HandleService<H> {
+    fn drop(&mut self) {
+        self.handler.on_teardown(&self.connection);
     }
 }
 
 impl<H: Handler> HandleService<H> {
-    fn call_not(&self, not: Notification) -> H::NotifyFuture {
-        self.0.handle_notification(&not.method, not.params, &self.1)
+    fn call_not(&self, not: Notification) -> HandleNotification<H::NotifyFuture> {
+        let Notification { method, params } = not;
+        let guard = self.connection.begin_notification();
+        let ctx = RequestContext::new(None, None, self.connection.clone());
+        let state = match panic::catch_unwind(AssertUnwindSafe(|| {
+            self.handler.handle_notification(&method, params, &self.client, &ctx)
+        })) {
+            Ok(future) => State::Running(AssertUnwindSafe(future).catch_unwind()),
+            Err(payload) => State::Panicked(payload),
+        };
+        HandleNotification {
+            state,
+            _guard: guard,
+            method,
+            hook: self.hook.clone(),
+        }
     }
 }
 
 
-/// An endpoint represents a peer of MessagePack-RPC.
-pub struct Endpoint {
-    rx_req: UnboundedReceiver<(u64, Request)>,
-    tx_res: UnboundedSender<(u64, Response)>,
-    rx_not: UnboundedReceiver<Notification>,
-    client: Client,
+enum State<F: Future> {
+    Running(CatchUnwind<AssertUnwindSafe<F>>),
+    Panicked(Box<Any + Send>),
+    Done,
 }
 
+impl<F: Future> State<F> {
+    /// Poll the inner future, and return the payload if the handler has panicked.
+    fn poll(&mut self) -> Poll<Result<F::Item, F::Error>, Box<Any + Send>> {
+        match ::std::mem::replace(self, State::Done) {
+            State::Running(mut future) => {
+                match future.poll() {
+                    Ok(Async::NotReady) => {
+                        *self = State::Running(future);
+                        Ok(Async::NotReady)
+                    }
+                    res => res,
+                }
+            }
+            State::Panicked(payload) => Err(payload),
+            State::Done => panic!("cannot poll a handler future twice"),
+        }
+    }
+}
+
+
+/// A future of the response to an incoming request, which never panics.
+struct HandleRequest<F: Future> {
+    state: State<F>,
+    timeout: Option<Timeout>,
+    guard: RequestGuard,
+    msgid: u64,
+    method: String,
+    hook: Option<ErrorHook>,
+}
+
+impl<F: Future<Item = Value, Error = Value>> Future for HandleRequest<F> {
+    type Item = Response;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let response = try_ready!(self.poll_response());
+        // The connection is kept until the response is written.
+        self.guard.respond();
+        Ok(Async::Ready(response))
+    }
+}
+
+impl<F: Future<Item = Value, Error = Value>> HandleRequest<F> {
+    fn poll_response(&mut self) -> Poll<Response, io::Error> {
+        match self.state.poll() {
+            Ok(Async::Ready(res)) => Ok(Async::Ready(Response::from(res))),
+            Ok(Async::NotReady) => {
+                if let Some(ref mut timeout) = self.timeout {
+                    if let Async::Ready(()) = timeout.poll()? {
+                        self.state = State::Done;
+                        return Ok(Async::Ready(Response::from_err("Request timed out")));
+                    }
+                }
+                Ok(Async::NotReady)
+            }
+            Err(payload) => {
+                let message = error::panic_message(&payload);
+                let response = Response::from_err(format!("Internal error: {}", message));
+                error::report(
+                    &self.hook,
+                    HandlerError::RequestPanicked {
+                        msgid: self.msgid,
+                        method: self.method.clone(),
+                        message,
+                    },
+                );
+                Ok(Async::Ready(response))
+            }
+        }
+    }
+}
+
+
+/// A future to handle an incoming notification, which never fails.
+///
+/// Panics and errors are reported to the hook, so that they don't stop the notification loop.
+struct HandleNotification<F: Future> {
+    state: State<F>,
+    _guard: NotificationGuard,
+    method: String,
+    hook: Option<ErrorHook>,
+}
+
+impl<F: Future<Item = (), Error = ()>> Future for HandleNotification<F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.state.poll() {
+            Ok(Async::Ready(Ok(()))) => Ok(Async::Ready(())),
+            Ok(Async::Ready(Err(()))) => {
+                error::report(
+                    &self.hook,
+                    HandlerError::NotificationFailed { method: self.method.clone() },
+                );
+                Ok(Async::Ready(()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(payload) => {
+                error::report(
+                    &self.hook,
+                    HandlerError::NotificationPanicked {
+                        method: self.method.clone(),
+                        message: error::panic_message(&payload),
+                    },
+                );
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+
+/// A builder to configure the wires of an `Endpoint`.
+///
+/// Incoming/outgoing messages are passed through bounded channels, one for each kind of
+/// message. When the handlers fall behind and the channel of incoming messages is full, the
+/// endpoint stops reading from the I/O until the handlers catch up.
+#[derive(Clone)]
+pub struct EndpointBuilder {
+    request_capacity: usize,
+    response_capacity: usize,
+    notification_capacity: usize,
+    max_in_flight: Option<usize>,
+    schedule: Schedule,
+    flush: FlushPolicy,
+    tap: Option<Rc<Tap>>,
+}
+
+impl fmt::Debug for EndpointBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EndpointBuilder")
+            .field("request_capacity", &self.request_capacity)
+            .field("response_capacity", &self.response_capacity)
+            .field("notification_capacity", &self.notification_capacity)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("schedule", &self.schedule)
+            .field("flush", &self.flush)
+            .field("tap", &self.tap.is_some())
+            .finish()
+    }
+}
+
+impl Default for EndpointBuilder {
+    fn default() -> Self {
+        EndpointBuilder {
+            request_capacity: 1024,
+            response_capacity: 1024,
+            notification_capacity: 1024,
+            max_in_flight: None,
+            schedule: Schedule::default(),
+            flush: FlushPolicy::default(),
+            tap: None,
+        }
+    }
+}
+
+impl EndpointBuilder {
+    /// Create a builder with the default configuration.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the capacity of the channels for requests, in both directions.
+    pub fn request_capacity(mut self, capacity: usize) -> Self {
+        self.request_capacity = capacity;
+        self
+    }
+
+    /// Set the capacity of the channels for responses, in both directions.
+    pub fn response_capacity(mut self, capacity: usize) -> Self {
+        self.response_capacity = capacity;
+        self
+    }
+
+    /// Set the capacity of the channels for notifications, in both directions.
+    pub fn notification_capacity(mut self, capacity: usize) -> Self {
+        self.notification_capacity = capacity;
+        self
+    }
+
+    /// Set the maximum number of incoming requests processed concurrently.
+    ///
+    /// While the limit is reached, the endpoint doesn't dispatch any more requests to the handler.
+    pub fn max_in_flight(mut self, n: usize) -> Self {
+        assert!(n > 0, "the maximum number of in-flight requests must be positive");
+        self.max_in_flight = Some(n);
+        self
+    }
+
+    /// Set the priority of outgoing lanes, from the highest to the lowest.
+    ///
+    /// The default order is `[Lane::Request, Lane::Response, Lane::Notification]`.
+    /// To keep the peer from waiting for responses under floods of notifications, put
+    /// `Lane::Response` first.
+    pub fn lane_priority(mut self, order: [Lane; 3]) -> Self {
+        self.schedule.set_priority(order);
+        self
+    }
+
+    /// Set the weight of an outgoing lane, which is the maximum number of messages taken from
+    /// the lane in a round of multiplexing.
+    ///
+    /// The default weight of each lane is `1`.
+    pub fn lane_weight(mut self, lane: Lane, weight: usize) -> Self {
+        self.schedule.set_weight(lane, weight);
+        self
+    }
+
+    /// Set the policy to flush outgoing messages.
+    ///
+    /// Batching small messages reduces the number of writes to the I/O, at the cost of latency.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush = policy;
+        self
+    }
+
+    /// Attach a tap, which observes every frame received from and sent to the peer.
+    ///
+    /// The tap is shared among all endpoints created by this builder.
+    pub fn tap<T: Tap>(mut self, tap: T) -> Self {
+        self.tap = Some(Rc::new(tap));
+        self
+    }
 
-impl Endpoint {
     /// Create a RPC endpoint from asyncrhonous I/O.
-    pub fn from_io<T: AsyncRead + AsyncWrite + 'static>(handle: &Handle, io: T) -> Self {
+    pub fn from_io<T: AsyncRead + AsyncWrite + 'static>(&self, handle: &Handle, io: T) -> Endpoint {
         let (read, write) = io.split();
+        self.from_parts(
+            handle,
+            FramedRead::new(read, Codec),
+            MessageWriter::new(write),
+        )
+    }
 
+    /// Create a RPC endpoint from a pair of stream/sink of messages.
+    ///
+    /// This is used for message-oriented transports, which carry a message per frame.
+    pub(crate) fn from_parts<T, U>(&self, handle: &Handle, stream: T, sink: U) -> Endpoint
+    where
+        T: Stream<Item = DecoderMessage, Error = io::Error> + 'static,
+        U: Sink<SinkItem = EncoderMessage, SinkError = io::Error> + Buffered + 'static,
+    {
         // create wires.
-        let stream = FramedRead::new(read, Codec);
-        let sink = FramedWrite::new(write, Codec);
-        let (d_tx0, d_rx0) = mpsc::unbounded();
-        let (d_tx1, d_rx1) = mpsc::unbounded();
-        let (d_tx2, d_rx2) = mpsc::unbounded();
-        let (m_tx0, m_rx0) = mpsc::unbounded();
-        let (m_tx1, m_rx1) = mpsc::unbounded();
-        let (m_tx2, m_rx2) = mpsc::unbounded();
+        let (d_tx0, d_rx0) = mpsc::channel(self.request_capacity);
+        let (d_tx1, d_rx1) = mpsc::channel(self.response_capacity);
+        let (d_tx2, d_rx2) = mpsc::channel(self.notification_capacity);
+        let (m_tx0, m_rx0) = mpsc::channel(self.request_capacity);
+        let (m_tx1, m_rx1) = mpsc::channel(self.response_capacity);
+        let (m_tx2, m_rx2) = mpsc::channel(self.notification_capacity);
 
         // start multiplexer/demultiplexer.
-        handle.spawn(Demux::new(stream, d_tx0, d_tx1, d_tx2));
-        handle.spawn(Mux::new(sink, m_rx0, m_rx1, m_rx2));
+        // The connection is regarded as closed when the peer stops sending messages, or when
+        // either of them fails.
+        // Both of them are dropped when the connection is aborted, which closes the I/O.
+        let connection = Connection::new();
+        let conn = connection.clone();
+        let stream = stream.map(move |msg| {
+            conn.touch();
+            match msg {
+                DecoderMessage::Response(..) => {}
+                _ => conn.enqueue(),
+            }
+            msg
+        });
+        let conn = connection.clone();
+        let demux = Demux::new(
+            stream,
+            d_tx0,
+            d_tx1,
+            d_tx2,
+            self.tap.clone(),
+            connection.id(),
+        );
+        handle.spawn(connection.abortable(demux).then(
+            move |res| -> Result<(), ()> {
+                conn.close(res.err());
+                Ok(())
+            },
+        ));
+        let conn = connection.clone();
+        let options = MuxOptions {
+            schedule: self.schedule,
+            flush: self.flush,
+            tap: self.tap.clone(),
+        };
+        let mux = Mux::new(sink, connection.clone(), options, handle, m_rx0, m_rx1, m_rx2);
+        handle.spawn(connection.abortable(mux).then(move |res| -> Result<(), ()> {
+            if let Err(err) = res {
+                conn.close(Some(err));
+            }
+            conn.finish_writer();
+            Ok(())
+        }));
 
         // start client
         let client = Client::new(handle, m_tx0, d_rx1, m_tx2);
@@ -127,14 +467,78 @@ impl Endpoint {
             tx_res: m_tx1,
             rx_not: d_rx2,
             client,
+            connection,
+            hook: None,
+            notification_concurrency: 1,
+            request_timeout: None,
+            max_in_flight: self.max_in_flight,
         }
     }
 
+    /// Create a pair of endpoints connected by an in-memory pipe.
+    ///
+    /// The options of the pipe can inject latency, partial reads and faults into the
+    /// conversation, which is useful for testing handlers.
+    pub fn pair(&self, handle: &Handle, options: PipeOptions) -> (Endpoint, Endpoint) {
+        let (a, b) = rpc_io::pipe(handle, options);
+        (self.from_io(handle, a), self.from_io(handle, b))
+    }
+}
+
+
+/// An endpoint represents a peer of MessagePack-RPC.
+pub struct Endpoint {
+    rx_req: Receiver<(u64, Request)>,
+    tx_res: Sender<(u64, Response)>,
+    rx_not: Receiver<Notification>,
+    client: Client,
+    connection: Connection,
+    hook: Option<ErrorHook>,
+    notification_concurrency: usize,
+    request_timeout: Option<Duration>,
+    max_in_flight: Option<usize>,
+}
+
+
+impl Endpoint {
+    /// Create a builder to configure an endpoint.
+    pub fn builder() -> EndpointBuilder {
+        EndpointBuilder::new()
+    }
+
+    /// Create a RPC endpoint from asyncrhonous I/O, with the default configuration.
+    pub fn from_io<T: AsyncRead + AsyncWrite + 'static>(handle: &Handle, io: T) -> Self {
+        EndpointBuilder::new().from_io(handle, io)
+    }
+
+    /// Create a pair of endpoints connected by an in-memory pipe, with the default
+    /// configuration.
+    ///
+    /// ```ignore
+    /// let (server, client) = Endpoint::pair(&handle);
+    /// server.serve(&handle, MyHandler::new());
+    /// let task = client.into_client().request("hello", vec![]);
+    /// ```
+    pub fn pair(handle: &Handle) -> (Endpoint, Endpoint) {
+        EndpointBuilder::new().pair(handle, PipeOptions::default())
+    }
+
     /// Return the reference of `Client` associated with the endpoint.
     pub fn client(&self) -> &Client {
         &self.client
     }
 
+    /// Return the connection associated with the endpoint.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Set the address of the peer, which is exposed to handlers via `RequestContext`.
+    pub fn with_peer_addr(self, addr: SocketAddr) -> Self {
+        self.connection.set_peer_addr(addr);
+        self
+    }
+
     /// Return the instance of `Client` associated with the endpoint.
     ///
     /// This function is useful if the endpoint doesn't handle any incoming requests/notifications.
@@ -143,6 +547,38 @@ impl Endpoint {
         self.client
     }
 
+    /// Register a hook to be called when a handler fails while serving requests/notifications.
+    ///
+    /// A panic inside the handler is caught per request/notification, and never stops the
+    /// endpoint. The peer receives an internal-error response for the panicked request.
+    /// Without the hook, the errors are logged.
+    pub fn on_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(HandlerError) + 'static,
+    {
+        self.hook = Some(Rc::new(hook));
+        self
+    }
+
+    /// Set the maximum number of notifications handled concurrently.
+    ///
+    /// The default value is `1`, which means that notifications are handled one at a time in the
+    /// order of arrival.
+    pub fn notification_concurrency(mut self, n: usize) -> Self {
+        assert!(n > 0, "the concurrency of notifications must be positive");
+        self.notification_concurrency = n;
+        self
+    }
+
+    /// Set the timeout of incoming requests.
+    ///
+    /// If a handler doesn't complete the request until the deadline, the peer receives an error
+    /// response and the future returned from the handler is dropped.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
     /// Start to serve incoming requests.
     ///
     /// This function does not block current thread, but returns an instance of `Client` associated
@@ -155,16 +591,40 @@ impl Endpoint {
     /// let _: Result<(), ()> = core.run(empty());
     /// ```
     pub fn serve<H: Handler>(self, handle: &Handle, handler: H) -> Client {
-        let service = Arc::new(HandleService(handler, self.client.clone()));
+        let service = Rc::new(HandleService {
+            handler,
+            client: self.client.clone(),
+            connection: self.connection,
+            handle: handle.clone(),
+            hook: self.hook,
+            request_timeout: self.request_timeout,
+        });
 
         let transport = EndpointTransport {
             stream: self.rx_req,
             sink: self.tx_res,
+            connection: service.connection.clone(),
+            max_in_flight: self.max_in_flight,
         };
 
+        service.handler.on_connect(&service.connection, &service.client);
+
         // Spawn services
         EndpointProto.bind_server(&handle, transport, service.clone());
-        handle.spawn(self.rx_not.for_each(move |not| service.call_not(not)));
+
+        let s = service.clone();
+        handle.spawn(service.connection.closed().then(move |_| {
+            let error = s.connection.error();
+            s.handler.on_disconnect(&s.connection, error.as_ref());
+            Ok(())
+        }));
+
+        handle.spawn(
+            self.rx_not
+                .map(move |not| service.call_not(not))
+                .buffer_unordered(self.notification_concurrency)
+                .for_each(|()| Ok(())),
+        );
 
         self.client
     }
@@ -0,0 +1,91 @@
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+
+/// An error occurred in a handler while serving requests/notifications.
+///
+/// These errors never stop the endpoint, and are reported through the hook registered by
+/// `Endpoint::on_error()`. Without the hook, they are logged instead.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The handler panicked while processing a request.
+    ///
+    /// The peer receives an internal-error response for the request.
+    RequestPanicked {
+        /// The ID of the request
+        msgid: u64,
+        /// The method name
+        method: String,
+        /// The message of the panic
+        message: String,
+    },
+
+    /// The handler panicked while processing a notification.
+    NotificationPanicked {
+        /// The method name
+        method: String,
+        /// The message of the panic
+        message: String,
+    },
+
+    /// The future returned from the handler of a notification has failed.
+    NotificationFailed {
+        /// The method name
+        method: String,
+    },
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandlerError::RequestPanicked {
+                msgid,
+                ref method,
+                ref message,
+            } => write!(f, "request {} ({:?}) panicked: {}", msgid, method, message),
+            HandlerError::NotificationPanicked {
+                ref method,
+                ref message,
+            } => write!(f, "notification ({:?}) panicked: {}", method, message),
+            HandlerError::NotificationFailed { ref method } => {
+                write!(f, "notification ({:?}) failed", method)
+            }
+        }
+    }
+}
+
+impl error::Error for HandlerError {
+    fn description(&self) -> &str {
+        match *self {
+            HandlerError::RequestPanicked { .. } => "request handler panicked",
+            HandlerError::NotificationPanicked { .. } => "notification handler panicked",
+            HandlerError::NotificationFailed { .. } => "notification handler failed",
+        }
+    }
+}
+
+
+pub(crate) type ErrorHook = Rc<Fn(HandlerError)>;
+
+/// Pass the error to the hook, or log it if no hook is registered.
+pub(crate) fn report(hook: &Option<ErrorHook>, err: HandlerError) {
+    match *hook {
+        Some(ref hook) => hook(err),
+        None => match err {
+            HandlerError::NotificationFailed { .. } => warn!("{}", err),
+            _ => error!("{}", err),
+        },
+    }
+}
+
+pub(crate) fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<Any>".to_owned()
+    }
+}
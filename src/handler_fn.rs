@@ -0,0 +1,67 @@
+use futures::IntoFuture;
+use rmpv::Value;
+
+use super::Handler;
+use super::client::Client;
+use super::context::RequestContext;
+
+
+/// A `Handler` built from a pair of closures, created by `handler_fn()`.
+pub struct HandlerFn<R, N> {
+    request: R,
+    notify: N,
+}
+
+/// Create a `Handler` from closures to handle requests and notifications.
+///
+/// ```ignore
+/// let handler = handler_fn(
+///     |method: &str, _params, _client: &Client, _ctx: &RequestContext| match method {
+///         "the_answer" => Ok(42u64.into()),
+///         m => Err(format!("The method is not found: {:?}", m).into()),
+///     },
+///     |_method: &str, _params, _client: &Client, _ctx: &RequestContext| Ok(()),
+/// );
+///
+/// endpoint.serve(&handle, handler);
+/// ```
+pub fn handler_fn<R, RF, N, NF>(request: R, notify: N) -> HandlerFn<R, N>
+where
+    R: Fn(&str, Value, &Client, &RequestContext) -> RF + 'static,
+    RF: IntoFuture<Item = Value, Error = Value>,
+    N: Fn(&str, Value, &Client, &RequestContext) -> NF + 'static,
+    NF: IntoFuture<Item = (), Error = ()>,
+{
+    HandlerFn { request, notify }
+}
+
+impl<R, RF, N, NF> Handler for HandlerFn<R, N>
+where
+    R: Fn(&str, Value, &Client, &RequestContext) -> RF + 'static,
+    RF: IntoFuture<Item = Value, Error = Value>,
+    N: Fn(&str, Value, &Client, &RequestContext) -> NF + 'static,
+    NF: IntoFuture<Item = (), Error = ()>,
+{
+    type RequestFuture = RF::Future;
+    type NotifyFuture = NF::Future;
+
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Value,
+        client: &Client,
+        ctx: &RequestContext,
+    ) -> Self::RequestFuture {
+        (self.request)(method, params, client, ctx).into_future()
+    }
+
+    fn handle_notification(
+        &self,
+        method: &str,
+        params: Value,
+        client: &Client,
+        ctx: &RequestContext,
+    ) -> Self::NotifyFuture {
+        (self.notify)(method, params, client, ctx).into_future()
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::sync::mpsc;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
+#[cfg(unix)]
+use tokio_uds::UnixDatagram;
+
+use super::super::{Endpoint, EndpointBuilder};
+use super::super::message::{DecoderMessage, EncoderMessage, Unbuffered};
+use super::super::util::io_error;
+#[cfg(unix)]
+use super::unix::{self, UnixBind};
+
+
+/// The maximum size of a datagram, which is the maximum payload of UDP over IPv4.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// The number of messages queued for each peer.
+const PEER_QUEUE_SIZE: usize = 16;
+
+/// The number of messages queued to be sent by a socket.
+const OUTGOING_QUEUE_SIZE: usize = 64;
+
+/// The default duration after which a peer without datagrams is forgotten.
+const DEFAULT_PEER_IDLE_TIMEOUT: u64 = 60;
+
+
+/// A non-blocking socket which carries a msgpack-rpc message in each datagram.
+pub(crate) trait DatagramSocket: 'static {
+    type Addr: Clone + Eq + Hash + fmt::Debug + 'static;
+
+    /// Receive a datagram, with the address of the sender if it can be replied to.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<Self::Addr>)>;
+
+    fn send_to(&self, buf: &[u8], addr: &Self::Addr) -> io::Result<usize>;
+
+    /// Return the address exposed to handlers via `RequestContext`.
+    fn peer_addr(addr: &Self::Addr) -> Option<SocketAddr>;
+}
+
+impl DatagramSocket for UdpSocket {
+    type Addr = SocketAddr;
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = UdpSocket::recv_from(self, buf)?;
+        Ok((len, Some(addr)))
+    }
+
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn peer_addr(addr: &SocketAddr) -> Option<SocketAddr> {
+        Some(*addr)
+    }
+}
+
+#[cfg(unix)]
+impl DatagramSocket for UnixDatagram {
+    type Addr = PathBuf;
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let (len, addr) = UnixDatagram::recv_from(self, buf)?;
+        Ok((len, addr.as_pathname().map(|path| path.to_owned())))
+    }
+
+    fn send_to(&self, buf: &[u8], addr: &PathBuf) -> io::Result<usize> {
+        UnixDatagram::send_to(self, buf, addr)
+    }
+
+    fn peer_addr(_: &PathBuf) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Receive the next valid message from the socket.
+///
+/// Invalid datagrams and datagrams which cannot be replied to are ignored.
+fn poll_recv<S: DatagramSocket>(
+    socket: &S,
+    buf: &mut [u8],
+) -> Poll<(S::Addr, DecoderMessage), io::Error> {
+    loop {
+        let (len, addr) = match socket.recv_from(buf) {
+            Ok(received) => received,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Async::NotReady)
+            }
+            // reported by some platforms when a previous datagram was not delivered.
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset ||
+                            err.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("ignored an error of the datagram socket: {}", err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                debug!("ignored a datagram from an unnamed socket");
+                continue;
+            }
+        };
+        match DecoderMessage::from_reader(&mut &buf[..len]) {
+            Ok(msg) => return Ok(Async::Ready((addr, msg))),
+            Err(_) => debug!("ignored an invalid datagram from {:?}", addr),
+        }
+    }
+}
+
+
+/// A task which sends the queued messages, one message per datagram.
+///
+/// A message which cannot be sent is logged and dropped, as datagrams are unreliable anyway.
+struct Outgoing<S: DatagramSocket> {
+    socket: Rc<S>,
+    rx: mpsc::Receiver<(S::Addr, EncoderMessage)>,
+    pending: Option<(S::Addr, Vec<u8>)>,
+}
+
+impl<S: DatagramSocket> Future for Outgoing<S> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some((addr, buf)) = self.pending.take() {
+                match self.socket.send_to(&buf, &addr) {
+                    Ok(_) => (),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        self.pending = Some((addr, buf));
+                        return Ok(Async::NotReady);
+                    }
+                    Err(err) => warn!("failed to send a datagram to {:?}: {}", addr, err),
+                }
+            }
+
+            let (addr, msg) = match try_ready!(self.rx.poll()) {
+                Some(item) => item,
+                None => return Ok(Async::Ready(())),
+            };
+            let mut buf = Vec::new();
+            if let Err(err) = msg.into_writer(&mut buf) {
+                warn!("failed to encode a message to {:?}: {}", addr, err);
+                continue;
+            }
+            if buf.len() > MAX_DATAGRAM_SIZE {
+                warn!(
+                    "dropped a message to {:?}: {} bytes exceeds the size of a datagram",
+                    addr,
+                    buf.len()
+                );
+                continue;
+            }
+            self.pending = Some((addr, buf));
+        }
+    }
+}
+
+
+/// The sink of an endpoint, which queues messages to its peer on the shared socket.
+struct PeerSink<A> {
+    addr: A,
+    tx: mpsc::Sender<(A, EncoderMessage)>,
+}
+
+impl<A: Clone> Sink for PeerSink<A> {
+    type SinkItem = EncoderMessage;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.tx.start_send((self.addr.clone(), item)) {
+            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
+            Ok(AsyncSink::NotReady((_, item))) => Ok(AsyncSink::NotReady(item)),
+            Err(_) => Err(socket_closed()),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.tx.poll_complete().map_err(|_| socket_closed())
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn socket_closed() -> io::Error {
+    io_error("the datagram socket has been closed")
+}
+
+/// Create an endpoint which exchanges messages with `addr` through the shared socket.
+fn peer_endpoint<S: DatagramSocket, T>(
+    builder: &EndpointBuilder,
+    handle: &Handle,
+    stream: T,
+    addr: S::Addr,
+    tx: mpsc::Sender<(S::Addr, EncoderMessage)>,
+) -> Endpoint
+where
+    T: Stream<Item = DecoderMessage, Error = io::Error> + 'static,
+{
+    let peer_addr = S::peer_addr(&addr);
+    let endpoint = builder.from_parts(handle, stream, Unbuffered(PeerSink { addr, tx }));
+    match peer_addr {
+        Some(addr) => endpoint.with_peer_addr(addr),
+        None => endpoint,
+    }
+}
+
+
+/// A stream of the messages sent from the peer of a connected datagram endpoint.
+struct Replies<S: DatagramSocket> {
+    socket: Rc<S>,
+    remote: S::Addr,
+    buf: Vec<u8>,
+}
+
+impl<S: DatagramSocket> Stream for Replies<S> {
+    type Item = DecoderMessage;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let (addr, msg) = try_ready!(poll_recv(&*self.socket, &mut self.buf));
+            if addr == self.remote {
+                return Ok(Async::Ready(Some(msg)));
+            }
+            debug!("ignored a datagram from an unknown peer {:?}", addr);
+        }
+    }
+}
+
+/// Create an endpoint which exchanges messages with `remote` only.
+fn connect<S: DatagramSocket>(
+    builder: &EndpointBuilder,
+    handle: &Handle,
+    socket: S,
+    remote: S::Addr,
+) -> Endpoint {
+    let socket = Rc::new(socket);
+    let (tx, rx) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+    handle.spawn(Outgoing {
+        socket: socket.clone(),
+        rx,
+        pending: None,
+    });
+    let replies = Replies {
+        socket,
+        remote: remote.clone(),
+        buf: vec![0; MAX_DATAGRAM_SIZE],
+    };
+    peer_endpoint::<S, _>(builder, handle, replies, remote, tx)
+}
+
+
+/// The limits of the peers tracked by a datagram socket.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerLimits {
+    pub max_peers: Option<usize>,
+    pub idle_timeout: Duration,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        PeerLimits {
+            max_peers: None,
+            idle_timeout: Duration::from_secs(DEFAULT_PEER_IDLE_TIMEOUT),
+        }
+    }
+}
+
+/// The channel to the endpoint of a peer.
+struct Peer {
+    tx: mpsc::Sender<DecoderMessage>,
+    last_seen: Instant,
+}
+
+/// A stream of endpoints, one for each address which sends datagrams to the socket.
+///
+/// A peer is forgotten when its endpoint is dropped, or when it sends no datagram for the idle
+/// timeout. The endpoint of a forgotten peer sees EOF, and a new endpoint is created at the
+/// next datagram from the address.
+pub(crate) struct Incoming<S: DatagramSocket> {
+    socket: Rc<S>,
+    handle: Handle,
+    builder: EndpointBuilder,
+    peers: HashMap<S::Addr, Peer>,
+    limits: PeerLimits,
+    timer: Option<Timeout>,
+    tx: mpsc::Sender<(S::Addr, EncoderMessage)>,
+    buf: Vec<u8>,
+}
+
+impl<S: DatagramSocket> Incoming<S> {
+    fn new(handle: &Handle, socket: S, builder: EndpointBuilder, limits: PeerLimits) -> Self {
+        let socket = Rc::new(socket);
+        let (tx, rx) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+        handle.spawn(Outgoing {
+            socket: socket.clone(),
+            rx,
+            pending: None,
+        });
+        let timer = Timeout::new(limits.idle_timeout, handle)
+            .map_err(|err| warn!("failed to create the timer to expire peers: {}", err))
+            .ok();
+        Incoming {
+            socket,
+            handle: handle.clone(),
+            builder,
+            peers: HashMap::new(),
+            limits,
+            timer,
+            tx,
+            buf: vec![0; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    /// Forget the peers whose endpoints are dropped or which are idle.
+    ///
+    /// Dropping the sender of an idle peer closes the incoming stream of its endpoint.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let idle_timeout = self.limits.idle_timeout;
+        self.peers.retain(|addr, peer| {
+            if peer.tx.is_closed() {
+                return false;
+            }
+            if now.duration_since(peer.last_seen) >= idle_timeout {
+                debug!("forgot an idle peer {:?}", addr);
+                return false;
+            }
+            true
+        });
+    }
+
+    fn poll_timer(&mut self) {
+        loop {
+            match self.timer.as_mut().map(|timer| timer.poll()) {
+                Some(Ok(Async::Ready(()))) => {}
+                Some(Ok(Async::NotReady)) | None => return,
+                Some(Err(err)) => {
+                    warn!("the timer to expire peers failed: {}", err);
+                    self.timer = None;
+                    return;
+                }
+            }
+            self.expire();
+            let at = Instant::now() + self.limits.idle_timeout;
+            if let Some(ref mut timer) = self.timer {
+                timer.reset(at);
+            }
+        }
+    }
+
+    /// Check whether a new peer can be tracked.
+    fn has_room(&mut self) -> bool {
+        let max = match self.limits.max_peers {
+            Some(max) => max,
+            None => return true,
+        };
+        if self.peers.len() >= max {
+            self.expire();
+        }
+        self.peers.len() < max
+    }
+}
+
+impl<S: DatagramSocket> Stream for Incoming<S> {
+    type Item = Endpoint;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.poll_timer();
+        loop {
+            let (addr, msg) = try_ready!(poll_recv(&*self.socket, &mut self.buf));
+            // pass the message to the endpoint of the peer, if it is alive.
+            let msg = match self.peers.get_mut(&addr) {
+                Some(peer) => {
+                    peer.last_seen = Instant::now();
+                    match peer.tx.start_send(msg) {
+                        Ok(AsyncSink::Ready) => None,
+                        Ok(AsyncSink::NotReady(_)) => {
+                            debug!("dropped a datagram from {:?}: the queue is full", addr);
+                            None
+                        }
+                        Err(err) => Some(err.into_inner()),
+                    }
+                }
+                None => Some(msg),
+            };
+            let msg = match msg {
+                Some(msg) => msg,
+                None => continue,
+            };
+
+            self.peers.remove(&addr);
+            if !self.has_room() {
+                debug!("dropped a datagram from {:?}: too many peers", addr);
+                continue;
+            }
+            let (mut tx, rx) = mpsc::channel(PEER_QUEUE_SIZE);
+            // a new channel always has room for a message.
+            let _ = tx.start_send(msg);
+            self.peers.insert(
+                addr.clone(),
+                Peer {
+                    tx,
+                    last_seen: Instant::now(),
+                },
+            );
+            let stream = rx.map_err(|()| -> io::Error { unreachable!() });
+            let endpoint = peer_endpoint::<S, _>(
+                &self.builder,
+                &self.handle,
+                stream,
+                addr,
+                self.tx.clone(),
+            );
+            return Ok(Async::Ready(Some(endpoint)));
+        }
+    }
+}
+
+/// Bind a UDP socket, and create an endpoint for each peer which sends datagrams to it.
+pub(crate) fn incoming_udp(
+    handle: &Handle,
+    addr: &SocketAddr,
+    builder: EndpointBuilder,
+    limits: PeerLimits,
+) -> io::Result<Incoming<UdpSocket>> {
+    let socket = UdpSocket::bind(addr, handle)?;
+    Ok(Incoming::new(handle, socket, builder, limits))
+}
+
+/// Bind a Unix datagram socket, and create an endpoint for each peer which sends datagrams to
+/// it.
+///
+/// The socket file is removed when the stream is dropped.
+#[cfg(unix)]
+pub(crate) fn incoming_unix(
+    handle: &Handle,
+    addr: &UnixBind,
+    builder: EndpointBuilder,
+    limits: PeerLimits,
+) -> io::Result<Box<Stream<Item = Endpoint, Error = io::Error>>> {
+    let (socket, file) = unix::bind_datagram(addr, handle)?;
+    let incoming = Incoming::new(handle, socket, builder, limits);
+    Ok(Box::new(incoming.map(move |endpoint| {
+        // the closure owns the socket file, to remove it with the socket.
+        let _ = &file;
+        endpoint
+    })))
+}
+
+
+impl EndpointBuilder {
+    /// Create a RPC endpoint which exchanges messages with `addr` over UDP, one message per
+    /// datagram.
+    ///
+    /// The socket is bound to an ephemeral port, and datagrams from other addresses are
+    /// ignored. Since UDP is unreliable, a request may never receive its response.
+    pub fn connect_udp(&self, handle: &Handle, addr: &SocketAddr) -> io::Result<Endpoint> {
+        let local = match *addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        let socket = UdpSocket::bind(&SocketAddr::new(local, 0), handle)?;
+        Ok(connect(self, handle, socket, *addr))
+    }
+
+    /// Create a RPC endpoint which exchanges messages with the Unix datagram socket at
+    /// `remote`, one message per datagram.
+    ///
+    /// The socket is bound to `local`, since the peer cannot reply to an unnamed socket.
+    #[cfg(unix)]
+    pub fn connect_unix_datagram<P, Q>(
+        &self,
+        handle: &Handle,
+        local: P,
+        remote: Q,
+    ) -> io::Result<Endpoint>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let socket = UnixDatagram::bind(local, handle)?;
+        Ok(connect(
+            self,
+            handle,
+            socket,
+            remote.as_ref().to_owned(),
+        ))
+    }
+}
+
+impl Endpoint {
+    /// Create a RPC endpoint over UDP, with the default configuration.
+    pub fn connect_udp(handle: &Handle, addr: &SocketAddr) -> io::Result<Endpoint> {
+        EndpointBuilder::new().connect_udp(handle, addr)
+    }
+
+    /// Create a RPC endpoint over Unix datagram sockets, with the default configuration.
+    #[cfg(unix)]
+    pub fn connect_unix_datagram<P, Q>(handle: &Handle, local: P, remote: Q) -> io::Result<Endpoint>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        EndpointBuilder::new().connect_unix_datagram(handle, local, remote)
+    }
+}
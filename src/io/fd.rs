@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use futures::{Async, Poll};
+use libc;
+use mio::{self, Evented, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use tokio_core::reactor::{Handle, PollEvented};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+
+/// The device and inode numbers of the file which a descriptor refers to.
+type FileId = (u64, u64);
+
+/// The original flags of the files switched to non-blocking mode, with the number of
+/// descriptors which refer to each of them.
+type Registry = Mutex<HashMap<FileId, (libc::c_int, usize)>>;
+
+fn registry() -> &'static Registry {
+    static INIT: Once = ONCE_INIT;
+    static mut REGISTRY: *const Registry = ptr::null();
+    unsafe {
+        INIT.call_once(|| {
+            REGISTRY = Box::into_raw(Box::new(Mutex::new(HashMap::new())));
+        });
+        &*REGISTRY
+    }
+}
+
+fn file_id(fd: RawFd) -> io::Result<FileId> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((stat.st_dev as u64, stat.st_ino as u64))
+}
+
+/// Return the original flags of the file, and count the descriptor as a user of them.
+fn acquire(fd: RawFd, id: FileId) -> io::Result<libc::c_int> {
+    let mut registry = registry().lock().unwrap();
+    if let Some(&mut (flags, ref mut count)) = registry.get_mut(&id) {
+        *count += 1;
+        return Ok(flags);
+    }
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    registry.insert(id, (flags, 1));
+    Ok(flags)
+}
+
+/// Restore the original flags of the file, if the descriptor is the last user of them.
+fn release(fd: RawFd, id: FileId) {
+    let mut registry = registry().lock().unwrap();
+    let flags = match registry.get_mut(&id) {
+        Some(&mut (flags, ref mut count)) => {
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            flags
+        }
+        None => return,
+    };
+    registry.remove(&id);
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFL, flags);
+    }
+}
+
+
+/// A file descriptor switched to non-blocking mode, to be registered with the reactor.
+///
+/// The original flags of the file descriptor are restored on drop, since they are shared
+/// with other processes (e.g. the terminal of the standard input). Descriptors of the same
+/// file (e.g. a terminal on both the standard input and output) share the flags, so they are
+/// captured by the first descriptor and restored after the last one is dropped.
+pub(crate) struct NonBlockingFd {
+    fd: RawFd,
+    id: FileId,
+    owned: bool,
+}
+
+impl NonBlockingFd {
+    /// Set the file descriptor to non-blocking mode.
+    ///
+    /// The file descriptor is not closed on drop.
+    pub(crate) fn new(fd: RawFd) -> io::Result<Self> {
+        let id = file_id(fd)?;
+        let flags = acquire(fd, id)?;
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            let err = io::Error::last_os_error();
+            release(fd, id);
+            return Err(err);
+        }
+        Ok(NonBlockingFd {
+            fd,
+            id,
+            owned: false,
+        })
+    }
+
+    /// Take the ownership of the file descriptor, and set it to non-blocking mode.
+    ///
+    /// The file descriptor is closed on drop, after its original flags are restored.
+    /// The file descriptor is also closed when this fails.
+    pub(crate) fn owned(fd: RawFd) -> io::Result<Self> {
+        let fd = OwnedFd(fd);
+        let mut nonblocking = Self::new(fd.0)?;
+        nonblocking.owned = true;
+        mem::forget(fd);
+        Ok(nonblocking)
+    }
+}
+
+impl Drop for NonBlockingFd {
+    fn drop(&mut self) {
+        release(self.fd, self.id);
+        if self.owned {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+/// A file descriptor which is closed on drop, unless it is released.
+struct OwnedFd(RawFd);
+
+impl OwnedFd {
+    fn release(self) -> RawFd {
+        let fd = self.0;
+        mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for OwnedFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+impl AsRawFd for NonBlockingFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Read for NonBlockingFd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+impl Write for NonBlockingFd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe {
+            libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len())
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for NonBlockingFd {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+
+/// An asynchronous I/O over a pair of file descriptors, such as pipes inherited from the
+/// parent process.
+///
+/// ```ignore
+/// // The host application passes a pipe pair on fds 3 and 4.
+/// let io = FdStream::new(&handle, 3, 4)?;
+/// let endpoint = Endpoint::from_io(&handle, io);
+/// ```
+pub struct FdStream {
+    reader: PollEvented<NonBlockingFd>,
+    writer: Option<PollEvented<NonBlockingFd>>,
+}
+
+impl FdStream {
+    /// Create an I/O which reads from `read_fd` and writes to `write_fd`.
+    ///
+    /// The stream takes the ownership of both file descriptors, and closes them on drop.
+    /// The same descriptor may be given twice (e.g. a socket). Both descriptors must be
+    /// pollable, so regular files are not supported.
+    pub fn new(handle: &Handle, read_fd: RawFd, write_fd: RawFd) -> io::Result<Self> {
+        // take the ownership of both descriptors first, so that they are closed on failure.
+        let read_fd = OwnedFd(read_fd);
+        let write_fd = if write_fd == read_fd.0 {
+            // a file descriptor cannot be registered to the reactor twice.
+            let fd = unsafe { libc::dup(read_fd.0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            OwnedFd(fd)
+        } else {
+            OwnedFd(write_fd)
+        };
+        let reader = PollEvented::new(NonBlockingFd::owned(read_fd.release())?, handle)?;
+        let writer = PollEvented::new(NonBlockingFd::owned(write_fd.release())?, handle)?;
+        Ok(FdStream {
+            reader,
+            writer: Some(writer),
+        })
+    }
+}
+
+impl Read for FdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl AsyncRead for FdStream {}
+
+impl Write for FdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer {
+            Some(ref mut writer) => writer.write(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the stream has been shut down",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsyncWrite for FdStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if let Some(writer) = self.writer.take() {
+            // A socket is shut down explicitly, since the reading descriptor may refer to the
+            // same socket. Otherwise, closing the writing descriptor lets the peer observe EOF.
+            let fd = writer.get_ref().as_raw_fd();
+            if unsafe { libc::shutdown(fd, libc::SHUT_WR) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::ENOTSOCK) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
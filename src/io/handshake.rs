@@ -0,0 +1,21 @@
+use std::io;
+use std::time::Duration;
+use futures::Future;
+use tokio_core::reactor::Handle;
+
+use super::super::util::Deadline;
+
+
+/// The default duration to wait for the handshake of an accepted connection, in seconds.
+pub(crate) const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Fail the handshake of an accepted connection when it takes longer than the timeout.
+///
+/// Handshakes are performed concurrently up to a limit, so a peer which never completes its
+/// handshake must not hold a slot forever.
+pub(crate) fn handshake<F>(inner: F, timeout: Duration, handle: &Handle) -> Deadline<F>
+where
+    F: Future<Error = io::Error>,
+{
+    Deadline::new(inner, handle, "the handshake has timed out").timeout(timeout)
+}
@@ -0,0 +1,273 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write, Cursor};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use futures::{Future, Poll, Async};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::super::message::{self, DecodeError};
+use super::super::tap::Direction;
+
+
+/// The fate of a frame written to an in-memory pipe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Deliver the frame as is.
+    Deliver,
+    /// Drop the frame silently.
+    Drop,
+    /// Deliver only the first given number of bytes of the frame.
+    ///
+    /// Frame boundaries are not preserved, so the reader decodes the following frames as the
+    /// rest of the truncated one.
+    Truncate(usize),
+}
+
+/// The options of an in-memory pipe, applied to both directions.
+#[derive(Clone, Default)]
+pub struct PipeOptions {
+    latency: Option<Duration>,
+    chunk_size: Option<usize>,
+    fault: Option<Rc<Fn(Direction, usize) -> Fault>>,
+}
+
+impl fmt::Debug for PipeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PipeOptions")
+            .field("latency", &self.latency)
+            .field("chunk_size", &self.chunk_size)
+            .field("fault", &self.fault.is_some())
+            .finish()
+    }
+}
+
+impl PipeOptions {
+    /// Create the options of a pipe which delivers every byte immediately.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Delay the delivery of each frame.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Set the maximum number of bytes returned by a read, in order to exercise partial reads.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "the chunk size must be positive");
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Set a function to decide the fate of each frame.
+    ///
+    /// The function receives the direction of the frame, seen from the first stream of the
+    /// pair, and the index of the frame in that direction.
+    ///
+    /// ```ignore
+    /// // Drop the second request from the first endpoint.
+    /// let options = PipeOptions::new().fault(|direction, index| match (direction, index) {
+    ///     (Direction::Outbound, 1) => Fault::Drop,
+    ///     _ => Fault::Deliver,
+    /// });
+    /// ```
+    pub fn fault<F: Fn(Direction, usize) -> Fault + 'static>(mut self, fault: F) -> Self {
+        self.fault = Some(Rc::new(fault));
+        self
+    }
+}
+
+
+/// A buffer of one direction of a pipe.
+struct Pipe {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    chunk_size: Option<usize>,
+    closed: bool,
+    reader: Option<Task>,
+}
+
+impl Pipe {
+    fn new(chunk_size: Option<usize>) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Pipe {
+            chunks: VecDeque::new(),
+            chunk_size,
+            closed: false,
+            reader: None,
+        }))
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+}
+
+
+/// One end of an in-memory duplex pipe, created by `pipe()`.
+///
+/// Bytes written to a stream are split into MessagePack frames, and then delivered to the
+/// other stream according to `PipeOptions`.
+pub struct MemoryStream {
+    rx: Rc<RefCell<Pipe>>,
+    tx: Rc<RefCell<Pipe>>,
+    handle: Handle,
+    timer: Option<Timeout>,
+    direction: Direction,
+    latency: Option<Duration>,
+    fault: Option<Rc<Fn(Direction, usize) -> Fault>>,
+    pending: Vec<u8>,
+    frames: usize,
+}
+
+/// Create a pair of connected in-memory streams.
+pub fn pipe(handle: &Handle, options: PipeOptions) -> (MemoryStream, MemoryStream) {
+    let a_to_b = Pipe::new(options.chunk_size);
+    let b_to_a = Pipe::new(options.chunk_size);
+    let a = MemoryStream {
+        rx: b_to_a.clone(),
+        tx: a_to_b.clone(),
+        handle: handle.clone(),
+        timer: None,
+        direction: Direction::Outbound,
+        latency: options.latency,
+        fault: options.fault.clone(),
+        pending: Vec::new(),
+        frames: 0,
+    };
+    let b = MemoryStream {
+        rx: a_to_b,
+        tx: b_to_a,
+        handle: handle.clone(),
+        timer: None,
+        direction: Direction::Inbound,
+        latency: options.latency,
+        fault: options.fault,
+        pending: Vec::new(),
+        frames: 0,
+    };
+    (a, b)
+}
+
+impl MemoryStream {
+    /// Split the complete frames out of the pending bytes.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let pos = {
+            let mut cursor = Cursor::new(&self.pending[..]);
+            match message::next_value(&mut cursor) {
+                Ok(_) => cursor.position() as usize,
+                Err(DecodeError::Truncated) => return None,
+                // A broken frame is passed through as is.
+                Err(_) => self.pending.len(),
+            }
+        };
+        if pos == 0 {
+            return None;
+        }
+        let rest = self.pending.split_off(pos);
+        Some(::std::mem::replace(&mut self.pending, rest))
+    }
+
+    fn send_frame(&mut self, mut frame: Vec<u8>) {
+        let fault = match self.fault {
+            Some(ref fault) => fault(self.direction, self.frames),
+            None => Fault::Deliver,
+        };
+        self.frames += 1;
+        match fault {
+            Fault::Deliver => (),
+            Fault::Drop => return,
+            Fault::Truncate(len) => frame.truncate(len),
+        }
+        // An empty chunk would be regarded as EOF by the reader.
+        if frame.is_empty() {
+            return;
+        }
+
+        let ready_at = Instant::now() + self.latency.unwrap_or_default();
+        let mut tx = self.tx.borrow_mut();
+        tx.chunks.push_back((ready_at, frame));
+        if let Some(task) = tx.reader.take() {
+            task.notify();
+        }
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.borrow_mut();
+        let ready_at = match rx.chunks.front() {
+            Some(&(ready_at, _)) => ready_at,
+            None if rx.closed => return Ok(0),
+            None => {
+                rx.reader = Some(task::current());
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Not ready"));
+            }
+        };
+
+        if ready_at > Instant::now() {
+            let mut timer = Timeout::new_at(ready_at, &self.handle)?;
+            if let Async::NotReady = timer.poll()? {
+                self.timer = Some(timer);
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Not ready"));
+            }
+        }
+        self.timer = None;
+
+        let limit = rx.chunk_size.unwrap_or(usize::MAX);
+        let (len, consumed) = {
+            let chunk = &mut rx.chunks.front_mut().unwrap().1;
+            let len = cmp::min(cmp::min(chunk.len(), buf.len()), limit);
+            buf[..len].copy_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            (len, chunk.is_empty())
+        };
+        if consumed {
+            rx.chunks.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.tx.borrow().closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the pipe has been closed",
+            ));
+        }
+        self.pending.extend_from_slice(buf);
+        while let Some(frame) = self.next_frame() {
+            self.send_frame(frame);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl AsyncWrite for MemoryStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.tx.borrow_mut().close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.tx.borrow_mut().close();
+        self.rx.borrow_mut().close();
+    }
+}
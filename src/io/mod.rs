@@ -2,46 +2,176 @@
 //! definition of I/O streams and helper functions.
 //!
 
+#[cfg(unix)]
+mod fd;
+mod datagram;
+#[cfg(any(feature = "tls", feature = "websocket"))]
+mod handshake;
+mod memory;
 mod stdio;
 mod process;
+mod server;
+#[cfg(unix)]
+pub(crate) mod unix;
+#[cfg(unix)]
+mod systemd;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 
+#[cfg(unix)]
+pub use self::fd::FdStream;
+pub use self::memory::{pipe, Fault, MemoryStream, PipeOptions};
 pub use self::stdio::StdioStream;
-pub use self::process::ChildProcessStream;
+pub use self::process::{ChildProcessStream, ChildProcess, Supervisor, Supervise};
+pub use self::server::{ListenAddr, Overflow, Server, Serve, Shutdown};
+#[cfg(feature = "tls")]
+pub use self::tls::{load_certs, load_private_key, PeerCertificates};
+#[cfg(unix)]
+pub use self::unix::{PeerCredentials, UnixBind};
+#[cfg(unix)]
+pub use self::systemd::listen_fds;
 
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
-use futures::Stream;
-use futures::future::empty;
-use tokio_core::net::TcpListener;
 use tokio_core::reactor::Core;
-use super::{Endpoint, Handler};
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
+use super::{Client, Connection, Handler, HandlerFactory};
 
 /// Run the RPC server on standard input/standard output, with given handler.
-pub fn run_stdio<H: Handler>(handler: H, chunk_size: usize) {
-    let mut core = Core::new().unwrap();
+///
+/// This function returns when the standard input reaches EOF, all in-flight requests and
+/// notifications are completed, and the responses are written.
+pub fn run_stdio<H: Handler>(handler: H) -> io::Result<()> {
+    let mut core = Core::new()?;
     let handle = core.handle();
 
-    let io = StdioStream::new(chunk_size);
-    let endpoint = Endpoint::from_io(&handle, io);
+    let (server, _) = Server::new(shared(handler)).serve_stdio(&handle);
+    core.run(server)
+}
+
+/// Run the RPC server on a pair of inherited file descriptors, with given handler.
+///
+/// This function returns when `read_fd` reaches EOF, all in-flight requests and notifications
+/// are completed, and the responses are written.
+#[cfg(unix)]
+pub fn run_fds<H: Handler>(handler: H, read_fd: RawFd, write_fd: RawFd) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
 
-    endpoint.serve(&handle, handler);
-    core.run(empty::<(), ()>()).unwrap();
+    let (server, _) = Server::new(shared(handler)).serve_fds(&handle, read_fd, write_fd)?;
+    core.run(server)
 }
 
 /// Run the RPC server on TCP, with given handler.
-pub fn run_tcp<H: Handler>(handler: H, addr: &str) {
-    let handler = Arc::new(handler);
+///
+/// The handler is shared among all connections.
+pub fn run_tcp<H: Handler>(handler: H, addr: &str) -> io::Result<()> {
+    run_tcp_with_factory(shared(handler), addr)
+}
+
+/// Run the RPC server on TCP, with a handler created for each accepted connection.
+pub fn run_tcp_with_factory<F: HandlerFactory>(factory: F, addr: &str) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+
+    let (server, _) = Server::new(factory).serve_tcp(&handle, addr)?;
+    core.run(server)
+}
+
+/// Run the RPC server on UDP, with given handler.
+///
+/// Each message is carried by a datagram, and responses are sent back to the source address
+/// of each request.
+pub fn run_udp<H: Handler>(handler: H, addr: &str) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+
+    let (server, _) = Server::new(shared(handler)).serve_udp(&handle, addr)?;
+    core.run(server)
+}
+
+/// Run the RPC server on a Unix domain socket, with given handler.
+///
+/// The handler is shared among all connections.
+#[cfg(unix)]
+pub fn run_unix<H: Handler, A: Into<UnixBind>>(handler: H, addr: A) -> io::Result<()> {
+    run_unix_with_factory(shared(handler), addr)
+}
+
+/// Run the RPC server on a Unix domain socket, with a handler created for each accepted
+/// connection.
+#[cfg(unix)]
+pub fn run_unix_with_factory<F: HandlerFactory, A: Into<UnixBind>>(
+    factory: F,
+    addr: A,
+) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
 
-    let mut core = Core::new().unwrap();
+    let (server, _) = Server::new(factory).serve_unix(&handle, addr)?;
+    core.run(server)
+}
+
+/// Run the RPC server on the listening sockets passed by systemd socket activation, with
+/// given handler.
+///
+/// The handler is shared among all connections.
+#[cfg(unix)]
+pub fn run_systemd<H: Handler>(handler: H) -> io::Result<()> {
+    run_systemd_with_factory(shared(handler))
+}
+
+/// Run the RPC server on the listening sockets passed by systemd socket activation, with a
+/// handler created for each accepted connection.
+#[cfg(unix)]
+pub fn run_systemd_with_factory<F: HandlerFactory>(factory: F) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+
+    let (server, _) = Server::new(factory).serve_systemd(&handle)?;
+    core.run(server)
+}
+
+/// Run the RPC server on TLS over TCP, with given handler.
+///
+/// The handler is shared among all connections.
+#[cfg(feature = "tls")]
+pub fn run_tls<H: Handler>(handler: H, addr: &str, config: Arc<ServerConfig>) -> io::Result<()> {
+    let mut core = Core::new()?;
     let handle = core.handle();
 
-    let addr = addr.parse().unwrap();
-    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let (server, _) = Server::new(shared(handler)).serve_tls(&handle, addr, config)?;
+    core.run(server)
+}
+
+/// Run the RPC server on WebSocket, with given handler.
+///
+/// The handler is shared among all connections.
+#[cfg(feature = "websocket")]
+pub fn run_websocket<H: Handler>(handler: H, addr: &str) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+
+    let (server, _) = Server::new(shared(handler)).serve_websocket(&handle, addr)?;
+    core.run(server)
+}
+
+/// Create a factory which shares given handler among all connections.
+fn shared<H: Handler>(handler: H) -> Shared<H> {
+    Shared(Arc::new(handler))
+}
+
+struct Shared<H: Handler>(Arc<H>);
 
-    let server = listener.incoming().for_each(move |(sock, _addr)| {
-        let endpoint = Endpoint::from_io(&handle, sock);
-        endpoint.serve(&handle, handler.clone());
-        Ok(())
-    });
+impl<H: Handler> HandlerFactory for Shared<H> {
+    type Handler = Arc<H>;
 
-    core.run(server).unwrap();
+    fn new_handler(&self, _: &Connection, _: &Client) -> Self::Handler {
+        self.0.clone()
+    }
 }
@@ -1,75 +1,148 @@
-use std::ffi::OsStr;
-use std::io::{self, Read, Write};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader, Read, Write};
 use std::ops::{Deref, DerefMut};
-use std::process::{Command, Stdio};
-use futures::Poll;
-use tokio_core::reactor::Handle;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+use futures::{Future, Stream, Poll, Async};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
+use tokio_io::io::Lines;
+use tokio_process::{Child, ChildStdin, ChildStdout, ChildStderr, CommandExt};
+
+use super::super::{Endpoint, EndpointBuilder};
+
 
 /// A non-blocking stream to interact with child process.
 pub struct ChildProcessStream {
-    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: Option<ChildStdout>,
+    process: Option<ChildProcess>,
 }
 
 impl ChildProcessStream {
+    /// Spawn a child process with piped standard input/output.
+    ///
+    /// The standard error of the process is forwarded to the logger, so that a chatty child
+    /// never stalls on the full pipe.
     pub fn launch<S, I, A>(handle: &Handle, program: S, args: I) -> io::Result<Self>
     where
         S: AsRef<OsStr>,
         I: IntoIterator<Item = A>,
         A: AsRef<OsStr>,
     {
-        Self::from_builder(
+        let mut stream = Self::from_builder(
             handle,
             Command::new(program)
                 .args(args)
                 .stdin(Stdio::piped())
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped()),
-        )
+        )?;
+        if let Some(ref mut process) = stream.process {
+            process.forward_stderr(handle);
+        }
+        Ok(stream)
     }
 
+    /// Spawn a child process from a command, whose standard input/output must be piped.
+    ///
+    /// If the standard error is piped, it must be consumed by `ChildProcess::stderr_lines()`
+    /// or `ChildProcess::forward_stderr()`.
     pub fn from_builder(handle: &Handle, command: &mut Command) -> io::Result<Self> {
         let child = command.spawn_async(handle)?;
-        Ok(ChildProcessStream { child })
+        Self::from_child(child)
+    }
+
+    /// Create a stream from a spawned child process, whose standard input/output are piped.
+    pub fn from_child(mut child: Child) -> io::Result<Self> {
+        if child.stdin().is_none() {
+            return Err(not_piped("the standard input"));
+        }
+        if child.stdout().is_none() {
+            return Err(not_piped("the standard output"));
+        }
+        Ok(ChildProcessStream::from(child))
     }
 
+    /// Return the inner child process, with its standard input/output/error put back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the process has been taken by `take_process()`.
     pub fn into_inner(self) -> Child {
-        self.child
+        let mut process = self.process.expect("the child process has been taken");
+        let mut child = process.child.take().unwrap();
+        *child.stdin() = self.stdin;
+        *child.stdout() = self.stdout;
+        *child.stderr() = process.stderr.take();
+        child
+    }
+
+    /// Return the reference of the child process, if it has not been taken.
+    pub fn process(&self) -> Option<&ChildProcess> {
+        self.process.as_ref()
+    }
+
+    /// Return the mutable reference of the child process, if it has not been taken.
+    pub fn process_mut(&mut self) -> Option<&mut ChildProcess> {
+        self.process.as_mut()
+    }
+
+    /// Take the child process out of the stream, in order to observe its exit while the stream
+    /// is used by an `Endpoint`.
+    ///
+    /// After the process is taken, the stream can no longer be dereferenced to `Child`.
+    pub fn take_process(&mut self) -> Option<ChildProcess> {
+        self.process.take()
     }
 
     fn child_stdin(&mut self) -> io::Result<&mut ChildStdin> {
-        self.child.stdin().as_mut().ok_or_else(|| {
-            io::Error::new(io::ErrorKind::BrokenPipe, "stdin is not open")
-        })
+        self.stdin.as_mut().ok_or_else(
+            || not_piped("the standard input"),
+        )
     }
 
     fn child_stdout(&mut self) -> io::Result<&mut ChildStdout> {
-        self.child.stdout().as_mut().ok_or_else(|| {
-            io::Error::new(io::ErrorKind::BrokenPipe, "stdout is not open")
-        })
+        self.stdout.as_mut().ok_or_else(
+            || not_piped("the standard output"),
+        )
     }
 }
 
 impl From<Child> for ChildProcessStream {
-    fn from(child: Child) -> Self {
-        ChildProcessStream { child }
+    fn from(mut child: Child) -> Self {
+        ChildProcessStream {
+            stdin: child.stdin().take(),
+            stdout: child.stdout().take(),
+            process: Some(ChildProcess {
+                stderr: child.stderr().take(),
+                child: Some(child),
+                kill_on_drop: true,
+            }),
+        }
     }
 }
 
 impl Deref for ChildProcessStream {
     type Target = Child;
     fn deref(&self) -> &Self::Target {
-        &self.child
+        self.process.as_ref().expect("the child process has been taken")
     }
 }
 
 impl DerefMut for ChildProcessStream {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.child
+        self.process.as_mut().expect("the child process has been taken")
     }
 }
 
+fn not_piped(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        format!("{} of the child process is not piped", name),
+    )
+}
+
 impl Read for ChildProcessStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.child_stdout()?.read(buf)
@@ -93,3 +166,215 @@ impl AsyncWrite for ChildProcessStream {
         self.child_stdin()?.shutdown()
     }
 }
+
+
+/// A spawned child process, which is a future of its exit status.
+///
+/// The process is killed when this value is dropped, unless `kill_on_drop(false)` is set.
+pub struct ChildProcess {
+    child: Option<Child>,
+    stderr: Option<ChildStderr>,
+    kill_on_drop: bool,
+}
+
+impl ChildProcess {
+    /// Set whether the process is killed when this value is dropped.
+    pub fn kill_on_drop(&mut self, kill: bool) {
+        self.kill_on_drop = kill;
+    }
+
+    /// Take the standard error of the process as a stream of lines.
+    ///
+    /// Returns `None` if the standard error is not piped or has already been taken.
+    pub fn stderr_lines(&mut self) -> Option<Lines<BufReader<ChildStderr>>> {
+        self.stderr.take().map(
+            |stderr| ::tokio_io::io::lines(BufReader::new(stderr)),
+        )
+    }
+
+    /// Forward the standard error of the process to the logger, line by line.
+    ///
+    /// Each line is logged with the level `Info`, under the target `msgpack_rpc::process`.
+    pub fn forward_stderr(&mut self, handle: &Handle) {
+        let pid = self.id();
+        if let Some(lines) = self.stderr_lines() {
+            handle.spawn(
+                lines
+                    .for_each(move |line| {
+                        info!(target: "msgpack_rpc::process", "[{}] {}", pid, line);
+                        Ok(())
+                    })
+                    .map_err(move |err| {
+                        warn!(target: "msgpack_rpc::process", "[{}] stderr: {}", pid, err);
+                    }),
+            );
+        }
+    }
+}
+
+impl Deref for ChildProcess {
+    type Target = Child;
+    fn deref(&self) -> &Self::Target {
+        self.child.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ChildProcess {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.child.as_mut().unwrap()
+    }
+}
+
+impl Future for ChildProcess {
+    type Item = ExitStatus;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.child.as_mut().unwrap().poll()
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        if !self.kill_on_drop {
+            if let Some(child) = self.child.take() {
+                child.forget();
+            }
+        }
+    }
+}
+
+
+/// A supervisor of a child process, which respawns the process when it crashes.
+///
+/// A new `Endpoint` is created over each spawned process and passed to the callback:
+///
+/// ```ignore
+/// let task = Supervisor::new(&handle, "my-plugin", vec!["--rpc"])
+///     .max_restarts(5)
+///     .supervise(|endpoint| {
+///         endpoint.serve(&handle, PluginHandler::new());
+///     });
+/// core.run(task)?;
+/// ```
+pub struct Supervisor {
+    handle: Handle,
+    program: OsString,
+    args: Vec<OsString>,
+    builder: EndpointBuilder,
+    max_restarts: Option<usize>,
+    backoff: Duration,
+}
+
+impl Supervisor {
+    /// Create a supervisor of the command.
+    pub fn new<S, I, A>(handle: &Handle, program: S, args: I) -> Self
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        Supervisor {
+            handle: handle.clone(),
+            program: program.as_ref().to_owned(),
+            args: args.into_iter().map(|a| a.as_ref().to_owned()).collect(),
+            builder: EndpointBuilder::new(),
+            max_restarts: None,
+            backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Set the configuration of endpoints created for each process.
+    pub fn endpoint(mut self, builder: EndpointBuilder) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    /// Set the maximum number of restarts.
+    ///
+    /// By default, the process is restarted infinitely.
+    pub fn max_restarts(mut self, n: usize) -> Self {
+        self.max_restarts = Some(n);
+        self
+    }
+
+    /// Set the delay before each restart (default: 1 second).
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Start to supervise the process.
+    ///
+    /// The returned future is resolved with the exit status when the process exits
+    /// successfully, or when it has crashed more than `max_restarts` times.
+    pub fn supervise<F: FnMut(Endpoint)>(self, on_start: F) -> Supervise<F> {
+        Supervise {
+            supervisor: self,
+            on_start,
+            restarts: 0,
+            state: SuperviseState::Starting,
+        }
+    }
+
+    fn spawn(&self) -> io::Result<(Endpoint, ChildProcess)> {
+        let mut stream = ChildProcessStream::launch(&self.handle, &self.program, &self.args)?;
+        let process = stream.take_process().unwrap();
+        Ok((self.builder.from_io(&self.handle, stream), process))
+    }
+}
+
+enum SuperviseState {
+    Starting,
+    Running(ChildProcess),
+    Waiting(Timeout),
+}
+
+/// A future of a supervised process, created by `Supervisor::supervise()`.
+pub struct Supervise<F: FnMut(Endpoint)> {
+    supervisor: Supervisor,
+    on_start: F,
+    restarts: usize,
+    state: SuperviseState,
+}
+
+impl<F: FnMut(Endpoint)> Future for Supervise<F> {
+    type Item = ExitStatus;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                SuperviseState::Starting => {
+                    let (endpoint, process) = self.supervisor.spawn()?;
+                    (self.on_start)(endpoint);
+                    SuperviseState::Running(process)
+                }
+                SuperviseState::Running(ref mut process) => {
+                    let status = try_ready!(process.poll());
+                    if status.success() {
+                        return Ok(Async::Ready(status));
+                    }
+                    if let Some(max) = self.supervisor.max_restarts {
+                        if self.restarts >= max {
+                            return Ok(Async::Ready(status));
+                        }
+                    }
+                    self.restarts += 1;
+                    warn!(
+                        target: "msgpack_rpc::process",
+                        "the child process has crashed ({}), restarting",
+                        status
+                    );
+                    let timeout = Timeout::new(self.supervisor.backoff, &self.supervisor.handle)?;
+                    SuperviseState::Waiting(timeout)
+                }
+                SuperviseState::Waiting(ref mut timeout) => {
+                    try_ready!(timeout.poll());
+                    SuperviseState::Starting
+                }
+            };
+            self.state = next;
+        }
+    }
+}
@@ -0,0 +1,826 @@
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use futures::{Future, Stream, Poll, Async};
+use futures::future::{self, JoinAll};
+use futures::stream;
+use futures::task::{self, AtomicTask};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use rmpv::Value;
+
+use super::StdioStream;
+#[cfg(unix)]
+use super::FdStream;
+use super::datagram;
+#[cfg(unix)]
+use super::unix::{self, UnixBind};
+#[cfg(unix)]
+use super::systemd;
+#[cfg(unix)]
+use tokio_uds::UnixListener;
+#[cfg(any(feature = "tls", feature = "websocket"))]
+use super::handshake::DEFAULT_HANDSHAKE_TIMEOUT;
+#[cfg(feature = "tls")]
+use super::tls;
+#[cfg(feature = "websocket")]
+use super::websocket;
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
+use super::super::{Ack, Client, Connection, Endpoint, EndpointBuilder, Extensions,
+                   HandlerFactory};
+
+
+/// An address for a server to listen on.
+///
+/// Addresses can be parsed from strings, where Unix domain sockets are prefixed with `unix:`
+/// (e.g. `"127.0.0.1:6666"` or `"unix:/run/myapp.sock"`).
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    /// A TCP address
+    Tcp(SocketAddr),
+    /// The address of a Unix domain socket, with its options
+    #[cfg(unix)]
+    Unix(UnixBind),
+}
+
+impl ListenAddr {
+    fn bind(&self, handle: &Handle) -> io::Result<Listener> {
+        match *self {
+            ListenAddr::Tcp(ref addr) => TcpListener::bind(addr, handle).map(Listener::Tcp),
+            #[cfg(unix)]
+            ListenAddr::Unix(ref addr) => {
+                let (listener, file) = unix::bind(addr, handle)?;
+                Ok(Listener::Unix(listener, Some(file)))
+            }
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixBind> for ListenAddr {
+    fn from(addr: UnixBind) -> Self {
+        ListenAddr::Unix(addr)
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = s.strip_prefix("unix:") {
+                return Ok(ListenAddr::Unix(UnixBind::new(path)));
+            }
+        }
+        s.parse().map(ListenAddr::Tcp).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        })
+    }
+}
+
+/// A bound listener.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, Option<unix::SocketFile>),
+}
+
+
+/// The behavior of a server when the maximum number of connections is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop accepting connections until one of the connections is closed.
+    ///
+    /// Pending connections are kept in the backlog of the listeners.
+    Queue,
+    /// Accept extra connections and close them immediately.
+    Reject,
+}
+
+
+/// A builder of RPC servers, which supports graceful shutdown.
+///
+/// ```ignore
+/// let (server, shutdown) = Server::new(factory)
+///     .grace_period(Duration::from_secs(5))
+///     .goodbye("shutdown", Vec::<Value>::new())
+///     .serve_tcp(&handle, "127.0.0.1:6666")?;
+///
+/// // Call `shutdown.shutdown()` from anywhere (e.g. a signal handler) to stop the server.
+/// core.run(server)?;
+/// ```
+pub struct Server<F: HandlerFactory> {
+    factory: F,
+    builder: EndpointBuilder,
+    grace_period: Option<Duration>,
+    goodbye: Option<(String, Value)>,
+    max_connections: Option<(usize, Overflow)>,
+    idle_timeout: Option<Duration>,
+    on_accept_error: Option<AcceptErrorHook>,
+    peer_limits: datagram::PeerLimits,
+    #[cfg(any(feature = "tls", feature = "websocket"))]
+    handshake_timeout: Duration,
+    #[cfg(feature = "websocket")]
+    check_origin: Option<websocket::CheckOrigin>,
+}
+
+impl<F: HandlerFactory> Server<F> {
+    /// Create a new server, with a factory to create a handler for each connection.
+    pub fn new(factory: F) -> Self {
+        Server {
+            factory,
+            builder: EndpointBuilder::new(),
+            grace_period: None,
+            goodbye: None,
+            max_connections: None,
+            idle_timeout: None,
+            on_accept_error: None,
+            peer_limits: datagram::PeerLimits::default(),
+            #[cfg(any(feature = "tls", feature = "websocket"))]
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT),
+            #[cfg(feature = "websocket")]
+            check_origin: None,
+        }
+    }
+
+    /// Set the configuration of endpoints created for each connection.
+    pub fn endpoint(mut self, builder: EndpointBuilder) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    /// Set the maximum duration to wait for in-flight requests after shutdown is requested.
+    ///
+    /// By default, the server waits until all in-flight requests are completed.
+    pub fn grace_period(mut self, period: Duration) -> Self {
+        self.grace_period = Some(period);
+        self
+    }
+
+    /// Set a notification sent to every connected peer when shutdown is requested.
+    pub fn goodbye<S: Into<String>, P: Into<Value>>(mut self, method: S, params: P) -> Self {
+        self.goodbye = Some((method.into(), params.into()));
+        self
+    }
+
+    /// Set the maximum number of concurrent connections, and the behavior for extra
+    /// connections.
+    ///
+    /// Connections over TLS or WebSocket are counted once their handshakes are completed,
+    /// since the handshakes are limited separately. By default, the number of connections is
+    /// unlimited.
+    pub fn max_connections(mut self, n: usize, overflow: Overflow) -> Self {
+        assert!(n > 0, "the maximum number of connections must be positive");
+        self.max_connections = Some((n, overflow));
+        self
+    }
+
+    /// Close connections which have neither received, handled nor written a message for the
+    /// duration.
+    ///
+    /// Connections are never closed while they are handling requests or notifications.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set a function called with each error of accepting connections.
+    ///
+    /// The server keeps running after accept errors, and pauses accepting for a moment so
+    /// that persistent errors (e.g. too many open files) don't spin the event loop.
+    /// By default, the errors are logged.
+    pub fn on_accept_error<E: Fn(&io::Error) + 'static>(mut self, f: E) -> Self {
+        self.on_accept_error = Some(Rc::new(f));
+        self
+    }
+
+    /// Set the maximum number of peers tracked by datagram servers.
+    ///
+    /// Datagrams from new peers are dropped while the limit is reached. By default, the
+    /// number of peers is unlimited.
+    pub fn max_peers(mut self, n: usize) -> Self {
+        assert!(n > 0, "the maximum number of peers must be positive");
+        self.peer_limits.max_peers = Some(n);
+        self
+    }
+
+    /// Set the duration after which datagram servers forget a peer which sends no datagram.
+    ///
+    /// The endpoint of a forgotten peer is closed once its in-flight requests are completed.
+    /// The default is 60 seconds.
+    pub fn peer_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.peer_limits.idle_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum duration of the handshake of each connection over TLS or WebSocket.
+    ///
+    /// Connections which don't complete the handshake in time are closed. The default is 10
+    /// seconds.
+    #[cfg(any(feature = "tls", feature = "websocket"))]
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Set a function which decides whether to accept a WebSocket connection from the value
+    /// of its `Origin` header.
+    ///
+    /// Browsers send the origin of the page which opens the connection, so servers reachable
+    /// from browsers should accept only trusted origins. By default, every origin is
+    /// accepted.
+    #[cfg(feature = "websocket")]
+    pub fn check_origin<V: Fn(Option<&str>) -> bool + 'static>(mut self, f: V) -> Self {
+        self.check_origin = Some(Rc::new(f));
+        self
+    }
+
+    /// Start to serve on standard input/standard output.
+    ///
+    /// The returned future is resolved when the standard input reaches EOF, all in-flight
+    /// requests and notifications are completed, and the responses are written.
+    pub fn serve_stdio(self, handle: &Handle) -> (Serve, Shutdown) {
+        let io = StdioStream::from_handle(handle);
+        self.serve_incoming(handle, stream::once(Ok((io, None))))
+    }
+
+    /// Start to serve on a pair of file descriptors, which are closed when the server stops.
+    ///
+    /// The returned future is resolved when `read_fd` reaches EOF, all in-flight requests and
+    /// notifications are completed, and the responses are written.
+    #[cfg(unix)]
+    pub fn serve_fds(
+        self,
+        handle: &Handle,
+        read_fd: RawFd,
+        write_fd: RawFd,
+    ) -> io::Result<(Serve, Shutdown)> {
+        let io = FdStream::new(handle, read_fd, write_fd)?;
+        Ok(self.serve_incoming(handle, stream::once(Ok((io, None)))))
+    }
+
+    /// Start to serve on TCP, bound to given address.
+    pub fn serve_tcp(self, handle: &Handle, addr: &str) -> io::Result<(Serve, Shutdown)> {
+        let addr = addr.parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        })?;
+        let listener = TcpListener::bind(&addr, handle)?;
+        let incoming = listener.incoming().map(|(sock, addr)| (sock, Some(addr)));
+        Ok(self.serve_incoming(handle, incoming))
+    }
+
+    /// Start to serve on UDP, bound to given address.
+    ///
+    /// Each message is carried by a datagram. An endpoint is created for each source address,
+    /// and responses are sent back to that address.
+    pub fn serve_udp(self, handle: &Handle, addr: &str) -> io::Result<(Serve, Shutdown)> {
+        let addr = addr.parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        })?;
+        let incoming =
+            datagram::incoming_udp(handle, &addr, self.builder.clone(), self.peer_limits)?;
+        Ok(self.serve_endpoints(handle, incoming))
+    }
+
+    /// Start to serve on TLS over TCP, bound to given address.
+    ///
+    /// If the server requires client authentication, the verified certificates of each peer
+    /// are available as `PeerCertificates` in the extensions of the connection.
+    #[cfg(feature = "tls")]
+    pub fn serve_tls(
+        self,
+        handle: &Handle,
+        addr: &str,
+        config: Arc<ServerConfig>,
+    ) -> io::Result<(Serve, Shutdown)> {
+        let addr = addr.parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        })?;
+        let listener = TcpListener::bind(&addr, handle)?;
+        Ok(self.serve_tls_listener(handle, listener, config))
+    }
+
+    /// Start to serve on TLS over TCP, accepting connections from a bound listener.
+    ///
+    /// This is useful to serve on a port chosen by the OS, whose address is read from the
+    /// listener beforehand.
+    #[cfg(feature = "tls")]
+    pub fn serve_tls_listener(
+        self,
+        handle: &Handle,
+        listener: TcpListener,
+        config: Arc<ServerConfig>,
+    ) -> (Serve, Shutdown) {
+        let incoming = tls::incoming(handle, listener, config, self.handshake_timeout);
+        self.serve_connections(handle, incoming)
+    }
+
+    /// Start to serve on WebSocket, bound to given address.
+    ///
+    /// Each binary WebSocket message carries a msgpack-rpc message.
+    #[cfg(feature = "websocket")]
+    pub fn serve_websocket(self, handle: &Handle, addr: &str) -> io::Result<(Serve, Shutdown)> {
+        let addr = addr.parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        })?;
+        let incoming = websocket::incoming(
+            handle,
+            &addr,
+            self.builder.clone(),
+            self.handshake_timeout,
+            self.check_origin.clone(),
+        )?;
+        Ok(self.serve_endpoints(handle, incoming))
+    }
+
+    /// Start to serve on a Unix domain socket.
+    ///
+    /// The credentials of each peer are available as `PeerCredentials` in the extensions of
+    /// the connection. The socket file is removed when the server stops accepting.
+    #[cfg(unix)]
+    pub fn serve_unix<A: Into<UnixBind>>(
+        self,
+        handle: &Handle,
+        addr: A,
+    ) -> io::Result<(Serve, Shutdown)> {
+        let (listener, file) = unix::bind(&addr.into(), handle)?;
+        Ok(self.serve_connections(handle, unix::incoming(listener, Some(file))))
+    }
+
+    /// Start to serve on a Unix datagram socket.
+    ///
+    /// Each message is carried by a datagram. Peers must bind their sockets to a path, since
+    /// datagrams from unnamed sockets cannot be replied to and are ignored. The socket file is
+    /// removed when the server stops.
+    #[cfg(unix)]
+    pub fn serve_unix_datagram<A: Into<UnixBind>>(
+        self,
+        handle: &Handle,
+        addr: A,
+    ) -> io::Result<(Serve, Shutdown)> {
+        let incoming = datagram::incoming_unix(
+            handle,
+            &addr.into(),
+            self.builder.clone(),
+            self.peer_limits,
+        )?;
+        Ok(self.serve_endpoints(handle, incoming))
+    }
+
+    /// Start to serve on the listening sockets passed by systemd socket activation.
+    ///
+    /// Both TCP and Unix domain sockets are accepted. The environment variables of the
+    /// activation are removed, so the sockets are taken only once. This fails if no socket is
+    /// passed to the process.
+    #[cfg(unix)]
+    pub fn serve_systemd(self, handle: &Handle) -> io::Result<(Serve, Shutdown)> {
+        let listeners = systemd::listeners(handle)?;
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no listening socket is passed by systemd",
+            ));
+        }
+        Ok(self.serve_listeners(handle, listeners))
+    }
+
+    /// Start to serve on all of the given addresses at once.
+    ///
+    /// ```ignore
+    /// let addrs = vec!["127.0.0.1:6666".parse()?, "unix:/run/myapp.sock".parse()?];
+    /// let (server, shutdown) = Server::new(factory)
+    ///     .max_connections(1024, Overflow::Queue)
+    ///     .idle_timeout(Duration::from_secs(300))
+    ///     .serve_all(&handle, addrs)?;
+    /// ```
+    pub fn serve_all<I>(self, handle: &Handle, addrs: I) -> io::Result<(Serve, Shutdown)>
+    where
+        I: IntoIterator<Item = ListenAddr>,
+    {
+        let listeners = addrs
+            .into_iter()
+            .map(|addr| addr.bind(handle))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(self.serve_listeners(handle, listeners))
+    }
+
+    fn serve_listeners(self, handle: &Handle, listeners: Vec<Listener>) -> (Serve, Shutdown) {
+        let mut incoming: Incoming = Box::new(stream::empty());
+        for listener in listeners {
+            let accepted = match listener {
+                Listener::Tcp(listener) => {
+                    let connections = listener.incoming().map(|(sock, addr)| {
+                        (sock, Some(addr), Extensions::default())
+                    });
+                    accepted(self.builder.clone(), handle, connections)
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener, file) => {
+                    accepted(self.builder.clone(), handle, unix::incoming(listener, file))
+                }
+            };
+            incoming = Box::new(incoming.select(accepted));
+        }
+        self.serve_accepted(handle, incoming)
+    }
+
+    /// Start to serve connections from a stream of I/O and the address of its peer.
+    pub fn serve_incoming<S, T>(self, handle: &Handle, incoming: S) -> (Serve, Shutdown)
+    where
+        S: Stream<Item = (T, Option<SocketAddr>), Error = io::Error> + 'static,
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let incoming = incoming.map(|(io, addr)| (io, addr, Extensions::default()));
+        self.serve_connections(handle, incoming)
+    }
+
+    /// Start to serve connections, with the initial extensions of each connection.
+    pub(crate) fn serve_connections<S, T>(self, handle: &Handle, incoming: S) -> (Serve, Shutdown)
+    where
+        S: Stream<Item = (T, Option<SocketAddr>, Extensions), Error = io::Error> + 'static,
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let incoming = accepted(self.builder.clone(), handle, incoming);
+        self.serve_accepted(handle, incoming)
+    }
+
+    /// Start to serve endpoints created from accepted connections.
+    pub(crate) fn serve_endpoints<S>(self, handle: &Handle, incoming: S) -> (Serve, Shutdown)
+    where
+        S: Stream<Item = Endpoint, Error = io::Error> + 'static,
+    {
+        let incoming = incoming.map(|endpoint| Box::new(endpoint) as Box<Accepted>);
+        self.serve_accepted(handle, Box::new(incoming))
+    }
+
+    fn serve_accepted(self, handle: &Handle, incoming: Incoming) -> (Serve, Shutdown) {
+        let factory = self.factory;
+        let h = handle.clone();
+        let start = move |endpoint: Endpoint| {
+            let connection = endpoint.connection().clone();
+            let handler = factory.new_handler(&connection, endpoint.client());
+            let client = endpoint.serve(&h, handler);
+            (connection, client)
+        };
+
+        let shutdown = Shutdown(Arc::new(ShutdownInner {
+            requested: AtomicBool::new(false),
+            task: AtomicTask::new(),
+        }));
+        let serve = Serve {
+            incoming: Some(incoming),
+            start: Box::new(start),
+            connections: Vec::new(),
+            shutdown: shutdown.0.clone(),
+            state: State::Running,
+            grace_period: self.grace_period,
+            goodbye: self.goodbye,
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout,
+            idle_timer: None,
+            on_accept_error: self.on_accept_error,
+            accept_pause: None,
+            handle: handle.clone(),
+        };
+
+        (serve, shutdown)
+    }
+}
+
+
+/// A connection taken from a listener.
+trait Accepted {
+    /// Create the endpoint of the connection.
+    fn into_endpoint(self: Box<Self>) -> Endpoint;
+
+    /// Close the connection without serving it.
+    fn reject(self: Box<Self>, reason: &str);
+}
+
+impl Accepted for Endpoint {
+    fn into_endpoint(self: Box<Self>) -> Endpoint {
+        *self
+    }
+
+    fn reject(self: Box<Self>, reason: &str) {
+        self.connection().abort(reason);
+    }
+}
+
+/// An accepted connection whose endpoint has not been created yet, so that rejecting it
+/// costs no more than closing the I/O.
+struct Pending<T> {
+    builder: EndpointBuilder,
+    handle: Handle,
+    io: T,
+    addr: Option<SocketAddr>,
+    extensions: Extensions,
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> Accepted for Pending<T> {
+    fn into_endpoint(self: Box<Self>) -> Endpoint {
+        let Pending {
+            builder,
+            handle,
+            io,
+            addr,
+            extensions,
+        } = *self;
+        let mut endpoint = builder.from_io(&handle, io);
+        if let Some(addr) = addr {
+            endpoint = endpoint.with_peer_addr(addr);
+        }
+        *endpoint.connection().extensions_mut() = extensions;
+        endpoint
+    }
+
+    fn reject(self: Box<Self>, _: &str) {
+        // the I/O is closed when dropped.
+    }
+}
+
+/// A function called with each error of accepting connections.
+type AcceptErrorHook = Rc<Fn(&io::Error)>;
+
+/// A stream of accepted connections.
+type Incoming = Box<Stream<Item = Box<Accepted>, Error = io::Error>>;
+
+/// Take each accepted connection with its initial extensions, and defer the creation of its
+/// endpoint.
+fn accepted<S, T>(builder: EndpointBuilder, handle: &Handle, incoming: S) -> Incoming
+where
+    S: Stream<Item = (T, Option<SocketAddr>, Extensions), Error = io::Error> + 'static,
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let handle = handle.clone();
+    Box::new(incoming.map(move |(io, addr, extensions)| {
+        Box::new(Pending {
+            builder: builder.clone(),
+            handle: handle.clone(),
+            io,
+            addr,
+            extensions,
+        }) as Box<Accepted>
+    }))
+}
+
+
+struct ShutdownInner {
+    requested: AtomicBool,
+    task: AtomicTask,
+}
+
+/// A handle to request the shutdown of a running server.
+///
+/// The handle can be sent to other threads.
+#[derive(Clone)]
+pub struct Shutdown(Arc<ShutdownInner>);
+
+impl Shutdown {
+    /// Request the server to shut down.
+    ///
+    /// The server stops accepting new connections, sends the goodbye notification to connected
+    /// peers, and waits for in-flight requests until the grace period expires.
+    pub fn shutdown(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        self.0.task.notify();
+    }
+}
+
+
+enum State {
+    Running,
+    ShuttingDown {
+        goodbye: JoinAll<Vec<Ack>>,
+        deadline: Option<Timeout>,
+    },
+}
+
+/// A future of running server, created by `Server`.
+///
+/// The future is resolved when all connections are closed, or when the shutdown has been
+/// completed.
+pub struct Serve {
+    incoming: Option<Incoming>,
+    start: Box<FnMut(Endpoint) -> (Connection, Client)>,
+    connections: Vec<(Connection, Client)>,
+    shutdown: Arc<ShutdownInner>,
+    state: State,
+    grace_period: Option<Duration>,
+    goodbye: Option<(String, Value)>,
+    max_connections: Option<(usize, Overflow)>,
+    idle_timeout: Option<Duration>,
+    idle_timer: Option<Timeout>,
+    on_accept_error: Option<AcceptErrorHook>,
+    accept_pause: Option<Timeout>,
+    handle: Handle,
+}
+
+impl Serve {
+    fn start_shutdown(&mut self) -> io::Result<()> {
+        // stop accepting new connections.
+        self.incoming = None;
+
+        let acks = match self.goodbye {
+            Some((ref method, ref params)) => {
+                self.connections
+                    .iter()
+                    .filter(|&&(ref conn, _)| !conn.is_closed())
+                    .map(|&(_, ref client)| client.notify(method.as_str(), params.clone()))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let deadline = match self.grace_period {
+            Some(period) => Some(Timeout::new(period, &self.handle)?),
+            None => None,
+        };
+
+        self.state = State::ShuttingDown {
+            goodbye: future::join_all(acks),
+            deadline,
+        };
+        Ok(())
+    }
+
+    fn poll_incoming(&mut self) -> io::Result<()> {
+        if let Some(mut pause) = self.accept_pause.take() {
+            match pause.poll() {
+                Ok(Async::NotReady) => {
+                    self.accept_pause = Some(pause);
+                    return Ok(());
+                }
+                Ok(Async::Ready(())) => (),
+                Err(err) => warn!("failed to wait for the pause of accepting: {}", err),
+            }
+        }
+        let mut incoming = match self.incoming.take() {
+            Some(incoming) => incoming,
+            None => return Ok(()),
+        };
+        let mut active = self.connections
+            .iter()
+            .filter(|&&(ref conn, _)| !conn.is_closed())
+            .count();
+        loop {
+            let overflow = match self.max_connections {
+                Some((max, overflow)) if active >= max => Some(overflow),
+                _ => None,
+            };
+            // the listeners are polled again when one of the connections is closed.
+            if overflow == Some(Overflow::Queue) {
+                self.incoming = Some(incoming);
+                return Ok(());
+            }
+            match incoming.poll() {
+                Ok(Async::Ready(Some(accepted))) => {
+                    if overflow.is_some() {
+                        warn!("rejected a connection: too many connections");
+                        accepted.reject("too many connections");
+                        continue;
+                    }
+                    self.connections.push((self.start)(accepted.into_endpoint()));
+                    active += 1;
+                }
+                Ok(Async::Ready(None)) => return Ok(()),
+                Ok(Async::NotReady) => {
+                    self.incoming = Some(incoming);
+                    return Ok(());
+                }
+                Err(err) => {
+                    match self.on_accept_error {
+                        Some(ref f) => f(&err),
+                        None => warn!("failed to accept a connection: {}", err),
+                    }
+                    self.incoming = Some(incoming);
+                    self.pause_accepting();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Stop accepting for a moment, so that persistent accept errors don't spin the event
+    /// loop.
+    fn pause_accepting(&mut self) {
+        let pause = Timeout::new(Duration::from_millis(100), &self.handle).and_then(
+            |mut pause| pause.poll().map(|ready| (pause, ready)),
+        );
+        match pause {
+            Ok((pause, Async::NotReady)) => self.accept_pause = Some(pause),
+            Ok((_, Async::Ready(()))) => task::current().notify(),
+            Err(err) => {
+                // accept again on the next turn of the event loop, without the pause.
+                warn!("failed to pause accepting connections: {}", err);
+                task::current().notify();
+            }
+        }
+    }
+
+    /// Abort idle connections, and schedule the next check.
+    fn poll_idle(&mut self) -> io::Result<()> {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut next = None;
+        for &(ref conn, _) in &self.connections {
+            if conn.is_closed() || !conn.is_settled() {
+                continue;
+            }
+            let deadline = conn.last_activity() + timeout;
+            if deadline <= now {
+                debug!("closing the idle connection #{}", conn.id());
+                conn.abort("the connection has been idle");
+            } else if next.map(|next| deadline < next).unwrap_or(true) {
+                next = Some(deadline);
+            }
+        }
+        match next {
+            Some(deadline) => {
+                if let Some(ref mut timer) = self.idle_timer {
+                    timer.reset(deadline);
+                    timer.poll()?;
+                    return Ok(());
+                }
+                let mut timer = Timeout::new_at(deadline, &self.handle)?;
+                timer.poll()?;
+                self.idle_timer = Some(timer);
+            }
+            None => self.idle_timer = None,
+        }
+        Ok(())
+    }
+}
+
+impl Future for Serve {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.shutdown.task.register();
+        if let State::Running = self.state {
+            if self.shutdown.requested.load(Ordering::SeqCst) {
+                self.start_shutdown()?;
+            }
+        }
+
+        self.poll_incoming()?;
+        self.poll_idle()?;
+
+        // forget connections which are closed, after their last responses are written.
+        self.connections.retain(|&(ref conn, _)| {
+            !conn.is_closed() || !conn.is_settled()
+        });
+        for &(ref conn, _) in &self.connections {
+            conn.watch();
+        }
+
+        match self.state {
+            State::Running => {
+                if self.incoming.is_none() && self.connections.is_empty() {
+                    return Ok(Async::Ready(()));
+                }
+            }
+            State::ShuttingDown {
+                ref mut goodbye,
+                ref mut deadline,
+            } => {
+                if let Some(ref mut deadline) = *deadline {
+                    if let Async::Ready(()) = deadline.poll()? {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                // The failure of goodbye notifications means that the peer has gone.
+                let sent = match goodbye.poll() {
+                    Ok(Async::NotReady) => false,
+                    _ => true,
+                };
+                let idle = self.connections.iter().all(
+                    |&(ref conn, _)| conn.is_settled(),
+                );
+                if sent && idle {
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
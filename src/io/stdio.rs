@@ -1,11 +1,30 @@
 use std::cmp;
-use std::io::{self, Read, Write, Stdout};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 
 use bytes::BytesMut;
-use futures::{Future, Stream, Sink, Poll, Async};
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink};
 use futures::sync::mpsc;
+use futures::task::AtomicTask;
+use tokio_core::reactor::Handle;
+#[cfg(unix)]
+use tokio_core::reactor::PollEvented;
 use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use libc;
+
+#[cfg(unix)]
+use super::fd::NonBlockingFd;
+
+/// The size of chunks read by the helper thread, if the standard input is not pollable.
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024;
+
+/// The number of chunks queued to the writer thread, above which writes return `WouldBlock`.
+const STDOUT_QUEUE_CAPACITY: usize = 16;
 
 
 pub struct Stdin {
@@ -84,22 +103,182 @@ pub fn stdin(chunk_size: usize) -> Stdin {
 }
 
 
+struct StdoutShared {
+    pending: AtomicUsize,
+    error: Mutex<Option<io::Error>>,
+    task: AtomicTask,
+}
+
+/// A writer of the standard output, which never blocks the event loop.
+///
+/// Written bytes are queued to a writer thread. When the queue is full, `write` returns
+/// `WouldBlock` and the current task is notified once the writer thread catches up.
+pub struct Stdout {
+    tx_stdout: mpsc::Sender<Vec<u8>>,
+    shared: Arc<StdoutShared>,
+}
+
+impl Stdout {
+    /// Return the error of the writer thread, which is kept for the subsequent calls.
+    fn error(&self) -> io::Error {
+        match *self.shared.error.lock().unwrap() {
+            Some(ref err) => io::Error::new(err.kind(), err.to_string()),
+            None => io::Error::new(io::ErrorKind::BrokenPipe, "the writer thread has stopped"),
+        }
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.shared.error.lock().unwrap().is_some() {
+            return Err(self.error());
+        }
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        match self.tx_stdout.start_send(buf.to_vec()) {
+            Ok(AsyncSink::Ready) => Ok(buf.len()),
+            Ok(AsyncSink::NotReady(_)) => {
+                self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "Not ready"))
+            }
+            Err(_) => {
+                self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+                Err(self.error())
+            }
+        }
+    }
+
+    /// Wait until the writer thread has written all queued bytes.
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.task.register();
+        if self.shared.pending.load(Ordering::SeqCst) == 0 {
+            return Ok(());
+        }
+        if self.shared.error.lock().unwrap().is_some() {
+            return Err(self.error());
+        }
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "Not ready"))
+    }
+}
+
+impl AsyncWrite for Stdout {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub fn stdout() -> Stdout {
+    let (tx_stdout, rx_stdout) = mpsc::channel::<Vec<u8>>(STDOUT_QUEUE_CAPACITY);
+    let shared = Arc::new(StdoutShared {
+        pending: AtomicUsize::new(0),
+        error: Mutex::new(None),
+        task: AtomicTask::new(),
+    });
+
+    let s = shared.clone();
+    thread::spawn(move || {
+        let stdout = io::stdout();
+        let mut locked_stdout = stdout.lock();
+        for bytes in rx_stdout.wait() {
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(()) => break,
+            };
+            if let Err(err) = locked_stdout.write_all(&bytes).and_then(
+                |_| locked_stdout.flush(),
+            )
+            {
+                *s.error.lock().unwrap() = Some(err);
+                s.task.notify();
+                break;
+            }
+            s.pending.fetch_sub(1, Ordering::SeqCst);
+            s.task.notify();
+        }
+    });
+
+    Stdout { tx_stdout, shared }
+}
+
+
+enum Input {
+    Threaded(Stdin),
+    #[cfg(unix)]
+    Evented(PollEvented<NonBlockingFd>),
+}
+
+enum Output {
+    Threaded(Stdout),
+    #[cfg(unix)]
+    Evented(PollEvented<NonBlockingFd>),
+}
+
+/// An asynchronous I/O of standard input/standard output.
 pub struct StdioStream {
-    stdin: Stdin,
-    stdout: Stdout,
+    stdin: Input,
+    stdout: Output,
 }
 
 impl StdioStream {
+    /// Create an I/O which reads the standard input on a helper thread, in chunks of
+    /// `chunk_size` bytes, and writes the standard output on another helper thread.
     pub fn new(chunk_size: usize) -> Self {
-        let stdin = stdin(chunk_size);
-        let stdout = io::stdout();
+        StdioStream {
+            stdin: Input::Threaded(stdin(chunk_size)),
+            stdout: Output::Threaded(stdout()),
+        }
+    }
+
+    /// Create an I/O which registers the standard input/standard output with the reactor.
+    ///
+    /// Both file descriptors are switched to non-blocking mode while the I/O is alive.
+    /// If either of them cannot be polled (e.g. a regular file) or is a terminal, it falls
+    /// back to the same behavior as `StdioStream::new()`. Terminals are kept blocking, since
+    /// they are usually shared with the standard error and other processes.
+    #[cfg(unix)]
+    pub fn from_handle(handle: &Handle) -> Self {
+        let stdin = match evented(0, handle) {
+            Ok(fd) => Input::Evented(fd),
+            Err(_) => Input::Threaded(stdin(DEFAULT_CHUNK_SIZE)),
+        };
+        let stdout = match evented(1, handle) {
+            Ok(fd) => Output::Evented(fd),
+            Err(_) => Output::Threaded(stdout()),
+        };
         StdioStream { stdin, stdout }
     }
+
+    /// Create an I/O of standard input/standard output.
+    ///
+    /// On this platform, the standard input is always read on a helper thread.
+    #[cfg(not(unix))]
+    pub fn from_handle(_: &Handle) -> Self {
+        StdioStream::new(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+/// Register a standard stream with the reactor, unless it is a terminal.
+#[cfg(unix)]
+fn evented(fd: RawFd, handle: &Handle) -> io::Result<PollEvented<NonBlockingFd>> {
+    if unsafe { libc::isatty(fd) } != 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "the stream is a terminal"));
+    }
+    NonBlockingFd::new(fd).and_then(|fd| PollEvented::new(fd, handle))
 }
 
 impl Read for StdioStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.stdin.read(buf)
+        match self.stdin {
+            Input::Threaded(ref mut stdin) => stdin.read(buf),
+            #[cfg(unix)]
+            Input::Evented(ref mut stdin) => stdin.read(buf),
+        }
     }
 }
 
@@ -107,16 +286,28 @@ impl AsyncRead for StdioStream {}
 
 impl Write for StdioStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.stdout.write(buf)
+        match self.stdout {
+            Output::Threaded(ref mut stdout) => stdout.write(buf),
+            #[cfg(unix)]
+            Output::Evented(ref mut stdout) => stdout.write(buf),
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.stdout.flush()
+        match self.stdout {
+            Output::Threaded(ref mut stdout) => stdout.flush(),
+            #[cfg(unix)]
+            Output::Evented(ref mut stdout) => stdout.flush(),
+        }
     }
 }
 
 impl AsyncWrite for StdioStream {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        Ok(Async::Ready(()))
+        match self.stdout {
+            Output::Threaded(ref mut stdout) => stdout.shutdown(),
+            #[cfg(unix)]
+            Output::Evented(_) => Ok(Async::Ready(())),
+        }
     }
 }
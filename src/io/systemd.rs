@@ -0,0 +1,155 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::net;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_uds::UnixListener;
+use libc;
+
+use super::server::Listener;
+
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the file descriptors passed by systemd socket activation.
+///
+/// The descriptors are numbered from 3, as described by `sd_listen_fds(3)`. An empty vector is
+/// returned if the process is not socket-activated. The variables `LISTEN_PID`, `LISTEN_FDS`
+/// and `LISTEN_FDNAMES` are removed from the environment, so that the descriptors are taken
+/// only once and are not inherited by child processes.
+pub fn listen_fds() -> io::Result<Vec<RawFd>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+    // The sockets are passed to another process.
+    if pid.trim().parse::<u32>().map_err(invalid_env)? != unsafe { libc::getpid() } as u32 {
+        return Ok(Vec::new());
+    }
+    let n = fds.trim().parse::<RawFd>().map_err(invalid_env)?;
+
+    let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + n).collect();
+    for &fd in &fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(fds)
+}
+
+fn invalid_env<E>(_: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid environment variables of socket activation",
+    )
+}
+
+
+/// Take the listening sockets passed by socket activation, and register them to the reactor.
+pub(crate) fn listeners(handle: &Handle) -> io::Result<Vec<Listener>> {
+    // Take the ownership of all descriptors first, so that none of them is leaked on failure.
+    let fds: Vec<Fd> = listen_fds()?.into_iter().map(Fd).collect();
+    fds.into_iter().map(|fd| fd.into_listener(handle)).collect()
+}
+
+/// A descriptor which has not been converted to a listener yet.
+struct Fd(RawFd);
+
+impl Fd {
+    fn into_listener(self, handle: &Handle) -> io::Result<Listener> {
+        let fd = self.0;
+        if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the file descriptor {} is not a stream socket", fd),
+            ));
+        }
+        // Units with `Accept=yes` pass a connected socket instead.
+        if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the file descriptor {} is not a listening socket", fd),
+            ));
+        }
+        let family = socket_family(fd)?;
+        mem::forget(self);
+        match family {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+                let addr = listener.local_addr()?;
+                TcpListener::from_listener(listener, &addr, handle).map(Listener::Tcp)
+            }
+            libc::AF_UNIX => {
+                let listener = unsafe { StdUnixListener::from_raw_fd(fd) };
+                // The socket file belongs to systemd.
+                let listener = UnixListener::from_listener(listener, handle)?;
+                Ok(Listener::Unix(listener, None))
+            }
+            family => {
+                unsafe {
+                    libc::close(fd);
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "the file descriptor {} has an unsupported address family {}",
+                        fd,
+                        family
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn socket_option(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
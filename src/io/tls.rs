@@ -0,0 +1,176 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{Future, Stream};
+use futures::future;
+use rustls::{Certificate, ClientConfig, PrivateKey, ServerConfig, ServerSession, Session};
+use rustls::internal::pemfile;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use tokio_rustls::{ClientConfigExt, ServerConfigExt, TlsStream};
+use webpki::{DNSName, DNSNameRef};
+
+use super::super::{Connect, Endpoint, EndpointBuilder, Extensions};
+use super::handshake::handshake;
+
+
+/// The certificate chain presented by the peer of a TLS connection.
+///
+/// When the peer has been authenticated by a certificate, endpoints over TLS store this value
+/// in the extensions of the connection:
+///
+/// ```ignore
+/// // `trusted` is a set of the DER-encoded certificates of known clients.
+/// let known = ctx.extensions()
+///     .get::<PeerCertificates>()
+///     .and_then(|certs| certs.end_entity())
+///     .map_or(false, |der| trusted.contains(der));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerCertificates(pub Vec<Certificate>);
+
+impl PeerCertificates {
+    /// Return the DER encoding of the end-entity certificate, which identifies the peer.
+    pub fn end_entity(&self) -> Option<&[u8]> {
+        self.0.first().map(|cert| &cert.0[..])
+    }
+
+    fn of<S: Session>(session: &S) -> Option<Self> {
+        session.get_peer_certificates().map(PeerCertificates)
+    }
+}
+
+
+/// Load the certificates from a PEM file.
+pub fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    pemfile::certs(&mut reader).map_err(|_| invalid_pem("certificates"))
+}
+
+/// Load the first private key from a PEM file, encoded in either PKCS#8 or RSA.
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKey> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).map_err(
+        |_| invalid_pem("private key"),
+    )?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).map_err(
+            |_| invalid_pem("private key"),
+        )?;
+    }
+    keys.into_iter().next().ok_or_else(
+        || invalid_pem("private key"),
+    )
+}
+
+fn invalid_pem(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("failed to load {} from the PEM file", what),
+    )
+}
+
+
+/// A stream of accepted TLS connections.
+pub(crate) type Incoming = Box<
+    Stream<
+        Item = (TlsStream<TcpStream, ServerSession>, Option<SocketAddr>, Extensions),
+        Error = io::Error,
+    >,
+>;
+
+/// Accept TCP connections and perform TLS handshakes on them.
+///
+/// Failed handshakes, including those which take longer than `timeout`, are logged and
+/// skipped, so that a misbehaving peer never stops the listener.
+pub(crate) fn incoming(
+    handle: &Handle,
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    timeout: Duration,
+) -> Incoming {
+    let h = handle.clone();
+    let incoming = listener
+        .incoming()
+        .map(move |(sock, addr)| {
+            let handshake = handshake(config.accept_async(sock), timeout, &h);
+            handshake.then(move |res| match res {
+                Ok(stream) => {
+                    let mut extensions = Extensions::default();
+                    if let Some(certs) = PeerCertificates::of(stream.get_ref().1) {
+                        extensions.insert(certs);
+                    }
+                    Ok(Some((stream, Some(addr), extensions)))
+                }
+                Err(err) => {
+                    warn!("TLS handshake with {} failed: {}", addr, err);
+                    Ok(None)
+                }
+            })
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|conn| conn);
+    Box::new(incoming)
+}
+
+/// The maximum number of TLS handshakes performed concurrently.
+const MAX_HANDSHAKES: usize = 64;
+
+
+impl EndpointBuilder {
+    /// Connect to the peer over TLS, and create a RPC endpoint.
+    ///
+    /// `domain` is the DNS name used to verify the certificate of the server. The certificates
+    /// of the server are available as `PeerCertificates` in the extensions of the connection.
+    pub fn connect_tls(
+        &self,
+        handle: &Handle,
+        addr: &SocketAddr,
+        domain: &str,
+        config: Arc<ClientConfig>,
+    ) -> Connect {
+        let domain = match DNSNameRef::try_from_ascii_str(domain) {
+            Ok(domain) => DNSName::from(domain),
+            Err(()) => {
+                let err = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid DNS name: {:?}", domain),
+                );
+                return Connect::new(handle, future::err(err));
+            }
+        };
+        let builder = self.clone();
+        let h = handle.clone();
+        let addr = *addr;
+        Connect::new(
+            handle,
+            TcpStream::connect(&addr, handle)
+                .and_then(move |sock| config.connect_async(domain.as_ref(), sock))
+                .map(move |stream| {
+                    let certs = PeerCertificates::of(stream.get_ref().1);
+                    let endpoint = builder.from_io(&h, stream).with_peer_addr(addr);
+                    if let Some(certs) = certs {
+                        endpoint.connection().extensions_mut().insert(certs);
+                    }
+                    endpoint
+                }),
+        )
+    }
+}
+
+impl Endpoint {
+    /// Connect to the peer over TLS, with the default configuration.
+    pub fn connect_tls(
+        handle: &Handle,
+        addr: &SocketAddr,
+        domain: &str,
+        config: Arc<ClientConfig>,
+    ) -> Connect {
+        EndpointBuilder::new().connect_tls(handle, addr, domain, config)
+    }
+}
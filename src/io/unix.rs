@@ -0,0 +1,302 @@
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use futures::Stream;
+use tokio_core::reactor::Handle;
+use tokio_uds::{UnixDatagram, UnixListener, UnixStream};
+use libc;
+
+use super::super::{Endpoint, EndpointBuilder, Extensions};
+
+
+/// The address of a Unix domain socket to listen on, with its options.
+///
+/// ```ignore
+/// io::run_unix(handler, UnixBind::new("/run/myapp.sock").mode(0o660))?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct UnixBind {
+    path: PathBuf,
+    mode: Option<u32>,
+    remove_stale: bool,
+}
+
+impl UnixBind {
+    /// Create an address of the socket at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        UnixBind {
+            path: path.into(),
+            mode: None,
+            remove_stale: true,
+        }
+    }
+
+    /// Return the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set the permission bits of the socket file (e.g. `0o600`).
+    ///
+    /// The socket is created under a umask which allows no more than `mode`, so that it is
+    /// never reachable with wider permissions. By default, the permission is determined by
+    /// the umask of the process.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set whether a stale socket file left at the path is removed before binding.
+    ///
+    /// A socket file is regarded as stale when no process accepts connections or datagrams on it.
+    /// The default is `true`.
+    pub fn remove_stale(mut self, remove: bool) -> Self {
+        self.remove_stale = remove;
+        self
+    }
+}
+
+impl<'a> From<&'a str> for UnixBind {
+    fn from(path: &'a str) -> Self {
+        UnixBind::new(path)
+    }
+}
+
+impl From<String> for UnixBind {
+    fn from(path: String) -> Self {
+        UnixBind::new(path)
+    }
+}
+
+impl<'a> From<&'a Path> for UnixBind {
+    fn from(path: &'a Path) -> Self {
+        UnixBind::new(path)
+    }
+}
+
+impl From<PathBuf> for UnixBind {
+    fn from(path: PathBuf) -> Self {
+        UnixBind::new(path)
+    }
+}
+
+
+/// Bind a listener to the address, cleaning up a stale socket file.
+pub(crate) fn bind(addr: &UnixBind, handle: &Handle) -> io::Result<(UnixListener, SocketFile)> {
+    bind_with(
+        addr,
+        |path| net::UnixStream::connect(path).map(|_| ()),
+        |path| UnixListener::bind(path, handle),
+    )
+}
+
+/// Bind a datagram socket to the address, cleaning up a stale socket file.
+pub(crate) fn bind_datagram(
+    addr: &UnixBind,
+    handle: &Handle,
+) -> io::Result<(UnixDatagram, SocketFile)> {
+    bind_with(
+        addr,
+        |path| net::UnixDatagram::unbound()?.connect(path),
+        |path| UnixDatagram::bind(path, handle),
+    )
+}
+
+/// Bind a socket with `bind`, where `probe` succeeds if another socket is alive at the path.
+fn bind_with<S, P, B>(addr: &UnixBind, probe: P, bind: B) -> io::Result<(S, SocketFile)>
+where
+    P: FnOnce(&Path) -> io::Result<()>,
+    B: FnOnce(&Path) -> io::Result<S>,
+{
+    if addr.remove_stale {
+        remove_stale(&addr.path, probe)?;
+    }
+    let socket = {
+        let _umask = addr.mode.map(Umask::restrict);
+        bind(&addr.path)?
+    };
+    let file = SocketFile::new(&addr.path)?;
+    if let Some(mode) = addr.mode {
+        fs::set_permissions(&addr.path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok((socket, file))
+}
+
+/// Accept connections on the listener, with the credentials of each peer in the extensions.
+///
+/// The socket file is removed when the stream is dropped.
+pub(crate) fn incoming(
+    listener: UnixListener,
+    file: Option<SocketFile>,
+) -> Box<Stream<Item = (UnixStream, Option<SocketAddr>, Extensions), Error = io::Error>> {
+    Box::new(listener.incoming().map(move |(sock, _)| {
+        // the closure owns the socket file, to remove it with the listener.
+        let _ = &file;
+        let mut extensions = Extensions::default();
+        if let Ok(cred) = PeerCredentials::of(&sock) {
+            extensions.insert(cred);
+        }
+        (sock, None, extensions)
+    }))
+}
+
+/// The umask of the process, restored when dropped.
+struct Umask(libc::mode_t);
+
+impl Umask {
+    /// Restrict the umask so that created files have no more permission than `mode`.
+    ///
+    /// The umask is only narrowed, since it is shared with the other threads.
+    fn restrict(mode: u32) -> Self {
+        let old = unsafe { libc::umask(0o777) };
+        unsafe {
+            libc::umask(old | (!mode & 0o777) as libc::mode_t);
+        }
+        Umask(old)
+    }
+}
+
+impl Drop for Umask {
+    fn drop(&mut self) {
+        unsafe {
+            libc::umask(self.0);
+        }
+    }
+}
+
+/// A socket file created by `bind`, which is removed when dropped.
+pub(crate) struct SocketFile {
+    path: PathBuf,
+    id: (u64, u64),
+}
+
+impl SocketFile {
+    fn new(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(SocketFile {
+            path: path.to_owned(),
+            id: (metadata.dev(), metadata.ino()),
+        })
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        // Leave the file alone if another process has replaced it.
+        match fs::symlink_metadata(&self.path) {
+            Ok(ref metadata) if (metadata.dev(), metadata.ino()) == self.id => {
+                if let Err(err) = fs::remove_file(&self.path) {
+                    warn!("failed to remove {}: {}", self.path.display(), err);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn remove_stale<P: FnOnce(&Path) -> io::Result<()>>(path: &Path, probe: P) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists and is not a socket", path.display()),
+        ));
+    }
+
+    // Nobody accepts connections or datagrams on a stale socket.
+    match probe(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+
+/// Create a RPC endpoint connected to the Unix domain socket at `path`.
+pub(crate) fn connect<P: AsRef<Path>>(
+    builder: &EndpointBuilder,
+    handle: &Handle,
+    path: P,
+) -> io::Result<Endpoint> {
+    let sock = UnixStream::connect(path, handle)?;
+    let cred = PeerCredentials::of(&sock);
+    let endpoint = builder.from_io(handle, sock);
+    if let Ok(cred) = cred {
+        endpoint.connection().extensions_mut().insert(cred);
+    }
+    Ok(endpoint)
+}
+
+
+/// The credentials of the peer process of a Unix domain socket.
+///
+/// Endpoints over Unix domain sockets store this value in the extensions of the connection:
+///
+/// ```ignore
+/// let uid = ctx.extensions().get::<PeerCredentials>().map(|cred| cred.uid);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// The process ID of the peer, if the platform provides it
+    pub pid: Option<u32>,
+    /// The effective user ID of the peer
+    pub uid: u32,
+    /// The effective group ID of the peer
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// Look up the credentials of the peer connected to the socket.
+    pub fn of<S: AsRawFd>(sock: &S) -> io::Result<Self> {
+        peer_credentials(sock.as_raw_fd())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(fd: RawFd) -> io::Result<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: Some(cred.pid as u32),
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(fd: RawFd) -> io::Result<PeerCredentials> {
+    let mut uid: libc::uid_t = unsafe { mem::zeroed() };
+    let mut gid: libc::gid_t = unsafe { mem::zeroed() };
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: None,
+        uid,
+        gid,
+    })
+}
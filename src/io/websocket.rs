@@ -0,0 +1,241 @@
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::future;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use websocket::{ClientBuilder, OwnedMessage, WebSocketError};
+use websocket::async::Client;
+use websocket::server::upgrade::async::IntoWs;
+
+use super::super::{Connect, Endpoint, EndpointBuilder};
+use super::super::message::{DecoderMessage, EncoderMessage, Unbuffered};
+use super::super::util::io_error;
+use super::handshake::handshake;
+
+
+/// A transport which carries a msgpack-rpc message in each binary WebSocket message.
+///
+/// Text messages and invalid messages are logged and ignored, and pings are answered automatically.
+struct WebSocketTransport<T> {
+    inner: T,
+    pending: Option<OwnedMessage>,
+    pong: Option<Vec<u8>>,
+}
+
+impl<T> WebSocketTransport<T>
+where
+    T: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    fn new(inner: T) -> Self {
+        WebSocketTransport {
+            inner,
+            pending: None,
+            pong: None,
+        }
+    }
+
+    /// Try to pass the pending messages to the inner sink.
+    fn start_send_pending(&mut self) -> Poll<(), io::Error> {
+        if let Some(data) = self.pong.take() {
+            if let AsyncSink::NotReady(msg) =
+                self.inner.start_send(OwnedMessage::Pong(data)).map_err(
+                    ws_error,
+                )?
+            {
+                if let OwnedMessage::Pong(data) = msg {
+                    self.pong = Some(data);
+                }
+                return Ok(Async::NotReady);
+            }
+        }
+        if let Some(msg) = self.pending.take() {
+            if let AsyncSink::NotReady(msg) = self.inner.start_send(msg).map_err(ws_error)? {
+                self.pending = Some(msg);
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> Stream for WebSocketTransport<T>
+where
+    T: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    type Item = DecoderMessage;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let msg = match try_ready!(self.inner.poll().map_err(ws_error)) {
+                Some(msg) => msg,
+                None => return Ok(Async::Ready(None)),
+            };
+            match msg {
+                OwnedMessage::Binary(bytes) => {
+                    match DecoderMessage::from_reader(&mut &bytes[..]) {
+                        Ok(msg) => return Ok(Async::Ready(Some(msg))),
+                        Err(_) => {
+                            debug!("ignored an invalid WebSocket message of {} bytes", bytes.len())
+                        }
+                    }
+                }
+                OwnedMessage::Ping(data) => {
+                    self.pong = Some(data);
+                    if let Async::Ready(()) = self.start_send_pending()? {
+                        self.inner.poll_complete().map_err(ws_error)?;
+                    }
+                }
+                OwnedMessage::Close(_) => return Ok(Async::Ready(None)),
+                OwnedMessage::Text(_) => debug!("ignored a WebSocket text message"),
+                OwnedMessage::Pong(_) => (),
+            }
+        }
+    }
+}
+
+impl<T> Sink for WebSocketTransport<T>
+where
+    T: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    type SinkItem = EncoderMessage;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if let Async::NotReady = self.start_send_pending()? {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let mut bytes = Vec::new();
+        item.into_writer(&mut bytes)?;
+        self.pending = Some(OwnedMessage::Binary(bytes));
+        self.start_send_pending()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.start_send_pending());
+        self.inner.poll_complete().map_err(ws_error)
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_complete());
+        self.inner.close().map_err(ws_error)
+    }
+}
+
+fn ws_error(err: WebSocketError) -> io::Error {
+    match err {
+        WebSocketError::IoError(err) => err,
+        err => io_error(err.to_string()),
+    }
+}
+
+
+impl EndpointBuilder {
+    /// Create a RPC endpoint from an established WebSocket connection.
+    pub fn from_websocket(&self, handle: &Handle, client: Client<TcpStream>) -> Endpoint {
+        let peer_addr = client.get_ref().peer_addr().ok();
+        let (sink, stream) = WebSocketTransport::new(client).split();
+        let endpoint = self.from_parts(handle, stream, Unbuffered(sink));
+        match peer_addr {
+            Some(addr) => endpoint.with_peer_addr(addr),
+            None => endpoint,
+        }
+    }
+
+    /// Connect to the WebSocket server at `url` (e.g. `ws://127.0.0.1:8080/rpc`), and create
+    /// a RPC endpoint.
+    pub fn connect_websocket(&self, handle: &Handle, url: &str) -> Connect {
+        let builder = match ClientBuilder::new(url) {
+            Ok(builder) => builder,
+            Err(err) => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, err.to_string());
+                return Connect::new(handle, future::err(err));
+            }
+        };
+        let this = self.clone();
+        let h = handle.clone();
+        Connect::new(
+            handle,
+            builder.async_connect_insecure(handle).map_err(ws_error).map(
+                move |(client, _)| this.from_websocket(&h, client),
+            ),
+        )
+    }
+}
+
+impl Endpoint {
+    /// Connect to the WebSocket server at `url`, with the default configuration.
+    pub fn connect_websocket(handle: &Handle, url: &str) -> Connect {
+        EndpointBuilder::new().connect_websocket(handle, url)
+    }
+}
+
+
+/// A function which decides whether to accept a WebSocket connection from its `Origin`.
+pub(crate) type CheckOrigin = Rc<Fn(Option<&str>) -> bool>;
+
+/// Accept TCP connections and perform WebSocket handshakes on them, and create an endpoint
+/// for each of them.
+///
+/// Failed handshakes, including those which take longer than `timeout`, are logged and
+/// skipped, so that a misbehaving peer never stops the listener. Upgrade requests whose
+/// origin is not accepted by `check_origin` are rejected.
+pub(crate) fn incoming(
+    handle: &Handle,
+    addr: &SocketAddr,
+    builder: EndpointBuilder,
+    timeout: Duration,
+    check_origin: Option<CheckOrigin>,
+) -> io::Result<Box<Stream<Item = Endpoint, Error = io::Error>>> {
+    let listener = TcpListener::bind(addr, handle)?;
+    let h = handle.clone();
+    let incoming = listener
+        .incoming()
+        .map(move |(sock, addr)| {
+            let check_origin = check_origin.clone();
+            let upgrade = sock.into_ws()
+                .map_err(|(_, _, _, err)| {
+                    io_error(format!("invalid upgrade request: {:?}", err))
+                })
+                .and_then(move |upgrade| -> Box<Future<Item = Option<Client<TcpStream>>, Error = io::Error>> {
+                    let allowed = check_origin.map_or(true, |f| f(upgrade.origin()));
+                    if allowed {
+                        Box::new(upgrade.accept().map_err(ws_error).map(
+                            |(client, _)| Some(client),
+                        ))
+                    } else {
+                        warn!(
+                            "rejected a WebSocket connection from {} with origin {:?}",
+                            addr,
+                            upgrade.origin()
+                        );
+                        Box::new(upgrade.reject().then(|_| Ok(None)))
+                    }
+                });
+            handshake(upgrade, timeout, &h).then(move |res| -> io::Result<_> {
+                match res {
+                    Ok(client) => Ok(client),
+                    Err(err) => {
+                        warn!("WebSocket handshake with {} failed: {}", addr, err);
+                        Ok(None)
+                    }
+                }
+            })
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|client| client);
+    let h = handle.clone();
+    Ok(Box::new(
+        incoming.map(move |client| builder.from_websocket(&h, client)),
+    ))
+}
+
+/// The maximum number of WebSocket handshakes performed concurrently.
+const MAX_HANDSHAKES: usize = 64;
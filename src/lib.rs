@@ -7,7 +7,7 @@
 //!
 //! ```ignore
 //! // Create a client from an I/O.
-//! let endpoint = msgpack_rpc::Endpoint::from_io(&handle, StdioStream::new(4, 4));
+//! let endpoint = msgpack_rpc::Endpoint::from_io(&handle, StdioStream::from_handle(&handle));
 //! let client = endpoint.into_client();
 //!
 //! // Call a precedure and receive its response asynchronously.
@@ -39,6 +39,7 @@
 //!         method: &str,
 //!         params: Value,
 //!         client: &Client,
+//!         ctx: &RequestContext,
 //!     ) -> Self::RequestFuture {
 //!         // ...
 //!     }
@@ -48,6 +49,7 @@
 //!         method: &str,
 //!         params: Value,
 //!         client: &Client,
+//!         ctx: &RequestContext,
 //!     ) -> Self::NotifyFuture {
 //!         // ...
 //!     }
@@ -57,6 +59,8 @@
 //! // It will spawn a service to handle requests/notifications from a peer.
 //! endpoint.launch(&handle, RootHandler { /* ... */ });
 //! ```
+//!
+//! For small servers, `handler_fn()` builds a `Handler` from a pair of closures.
 
 extern crate bytes;
 #[macro_use]
@@ -66,19 +70,56 @@ extern crate tokio_io;
 extern crate tokio_proto;
 extern crate tokio_service;
 extern crate tokio_process;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate mio;
 extern crate rmpv;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "with-serde")]
+extern crate serde;
+#[cfg(feature = "tls")]
+pub extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate tokio_rustls;
+#[cfg(feature = "tls")]
+extern crate webpki;
+#[cfg(feature = "websocket")]
+extern crate websocket;
+
+#[cfg(feature = "with-serde")]
+#[macro_use]
+mod service;
 
 mod client;
+mod connect;
+mod context;
 mod distributor;
 mod endpoint;
+mod error;
+mod handler_fn;
 mod message;
 mod util;
 
 pub mod io;
+pub mod record;
+pub mod tap;
 
 pub use rmpv::Value;
 pub use self::client::{Client, Response, Ack};
-pub use self::endpoint::Endpoint;
+pub use self::connect::Connect;
+pub use self::context::{Connection, Cancellation, Extensions, RequestContext};
+pub use self::distributor::{FlushPolicy, Lane};
+pub use self::endpoint::{Endpoint, EndpointBuilder};
+pub use self::error::HandlerError;
+pub use self::handler_fn::{handler_fn, HandlerFn};
+
+#[cfg(feature = "with-serde")]
+#[doc(hidden)]
+pub use self::service::rt as __rt;
 
 use std::rc::Rc;
 use std::sync::Arc;
@@ -93,7 +134,13 @@ pub trait Handler: 'static {
     type NotifyFuture: Future<Item = (), Error = ()>;
 
     /// Handler function to handle a request.
-    fn handle_request(&self, method: &str, params: Value, client: &Client) -> Self::RequestFuture;
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Value,
+        client: &Client,
+        ctx: &RequestContext,
+    ) -> Self::RequestFuture;
 
     /// Handler function to handle a notification.
     fn handle_notification(
@@ -101,15 +148,71 @@ pub trait Handler: 'static {
         method: &str,
         params: Value,
         client: &Client,
+        ctx: &RequestContext,
     ) -> Self::NotifyFuture;
+
+    /// Called when the endpoint starts to serve the connection.
+    fn on_connect(&self, _connection: &Connection, _client: &Client) {}
+
+    /// Called when the connection has been closed.
+    ///
+    /// `error` is the cause of the closure, or `None` if the peer has closed the connection
+    /// cleanly.
+    fn on_disconnect(&self, _connection: &Connection, _error: Option<&::std::io::Error>) {}
+
+    /// Called when the handler is torn down, after the connection has been closed and all
+    /// requests/notifications from the peer have been completed.
+    fn on_teardown(&self, _connection: &Connection) {}
+}
+
+/// A factory of handlers, invoked for each accepted connection.
+///
+/// Since a fresh handler is created per connection, it can hold per-connection state (e.g.
+/// sessions, subscriptions or authorization) without interior mutability shared among
+/// connections.
+///
+/// Closures which take the connection and its `Client` implement this trait:
+///
+/// ```ignore
+/// io::run_tcp_with_factory(
+///     |conn: &Connection, client: &Client| Session::new(conn.peer_addr(), client.clone()),
+///     "127.0.0.1:6666",
+/// );
+/// ```
+pub trait HandlerFactory: 'static {
+    /// The type of handlers created by the factory.
+    type Handler: Handler;
+
+    /// Create a handler for the connection.
+    ///
+    /// The address of the peer is available via `Connection::peer_addr()`.
+    fn new_handler(&self, connection: &Connection, client: &Client) -> Self::Handler;
+}
+
+impl<F, H> HandlerFactory for F
+where
+    F: Fn(&Connection, &Client) -> H + 'static,
+    H: Handler,
+{
+    type Handler = H;
+
+    fn new_handler(&self, connection: &Connection, client: &Client) -> Self::Handler {
+        (*self)(connection, client)
+    }
 }
 
 impl<H: Handler> Handler for Box<H> {
     type RequestFuture = H::RequestFuture;
     type NotifyFuture = H::NotifyFuture;
 
-    fn handle_request(&self, method: &str, params: Value, client: &Client) -> Self::RequestFuture {
-        (**self).handle_request(method, params, client)
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Value,
+        client: &Client,
+        ctx: &RequestContext,
+    ) -> Self::RequestFuture {
+        (**self).handle_request(method, params, client, ctx)
     }
 
     fn handle_notification(
@@ -117,8 +220,21 @@ impl<H: Handler> Handler for Box<H> {
         method: &str,
         params: Value,
         client: &Client,
+        ctx: &RequestContext,
     ) -> Self::NotifyFuture {
-        (**self).handle_notification(method, params, client)
+        (**self).handle_notification(method, params, client, ctx)
+    }
+
+    fn on_connect(&self, connection: &Connection, client: &Client) {
+        (**self).on_connect(connection, client)
+    }
+
+    fn on_disconnect(&self, connection: &Connection, error: Option<&::std::io::Error>) {
+        (**self).on_disconnect(connection, error)
+    }
+
+    fn on_teardown(&self, connection: &Connection) {
+        (**self).on_teardown(connection)
     }
 }
 
@@ -126,8 +242,14 @@ impl<H: Handler> Handler for Rc<H> {
     type RequestFuture = H::RequestFuture;
     type NotifyFuture = H::NotifyFuture;
 
-    fn handle_request(&self, method: &str, params: Value, client: &Client) -> Self::RequestFuture {
-        (**self).handle_request(method, params, client)
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Value,
+        client: &Client,
+        ctx: &RequestContext,
+    ) -> Self::RequestFuture {
+        (**self).handle_request(method, params, client, ctx)
     }
 
     fn handle_notification(
@@ -135,8 +257,21 @@ impl<H: Handler> Handler for Rc<H> {
         method: &str,
         params: Value,
         client: &Client,
+        ctx: &RequestContext,
     ) -> Self::NotifyFuture {
-        (**self).handle_notification(method, params, client)
+        (**self).handle_notification(method, params, client, ctx)
+    }
+
+    fn on_connect(&self, connection: &Connection, client: &Client) {
+        (**self).on_connect(connection, client)
+    }
+
+    fn on_disconnect(&self, connection: &Connection, error: Option<&::std::io::Error>) {
+        (**self).on_disconnect(connection, error)
+    }
+
+    fn on_teardown(&self, connection: &Connection) {
+        (**self).on_teardown(connection)
     }
 }
 
@@ -144,8 +279,14 @@ impl<H: Handler> Handler for Arc<H> {
     type RequestFuture = H::RequestFuture;
     type NotifyFuture = H::NotifyFuture;
 
-    fn handle_request(&self, method: &str, params: Value, client: &Client) -> Self::RequestFuture {
-        (**self).handle_request(method, params, client)
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Value,
+        client: &Client,
+        ctx: &RequestContext,
+    ) -> Self::RequestFuture {
+        (**self).handle_request(method, params, client, ctx)
     }
 
     fn handle_notification(
@@ -153,7 +294,20 @@ impl<H: Handler> Handler for Arc<H> {
         method: &str,
         params: Value,
         client: &Client,
+        ctx: &RequestContext,
     ) -> Self::NotifyFuture {
-        (**self).handle_notification(method, params, client)
+        (**self).handle_notification(method, params, client, ctx)
+    }
+
+    fn on_connect(&self, connection: &Connection, client: &Client) {
+        (**self).on_connect(connection, client)
+    }
+
+    fn on_disconnect(&self, connection: &Connection, error: Option<&::std::io::Error>) {
+        (**self).on_disconnect(connection, error)
+    }
+
+    fn on_teardown(&self, connection: &Connection) {
+        (**self).on_teardown(connection)
     }
 }
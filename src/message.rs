@@ -1,13 +1,19 @@
 use std::io::{self, Read, Write};
+use futures::{Sink, Poll, Async, AsyncSink, StartSend};
 use futures::sync::oneshot;
 use bytes::{BufMut, BytesMut};
+use tokio_io::AsyncWrite;
 use tokio_io::codec::{Encoder, Decoder};
 use rmpv::{self, Value};
+use super::tap::Message;
 
 const REQUEST_TYPE: i64 = 0;
 const RESPONSE_TYPE: i64 = 1;
 const NOTIFICATION_TYPE: i64 = 2;
 
+/// The number of bytes reserved in the buffer before encoding a message.
+const RESERVE_SIZE: usize = 1024;
+
 
 /// A codec for `Message`.
 pub struct Codec;
@@ -17,7 +23,24 @@ impl Encoder for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        msg.into_writer(&mut buf.writer())
+        let packet = msg.to_packet();
+        // `BufMut::writer()` doesn't grow the buffer, so retry with more room if the packet
+        // doesn't fit.
+        let mut reserve = RESERVE_SIZE;
+        loop {
+            let len = buf.len();
+            buf.reserve(reserve);
+            match write_packet(&mut (&mut *buf).writer(), &packet) {
+                Ok(()) => break,
+                Err(ref err) if err.kind() == io::ErrorKind::WriteZero => {
+                    buf.truncate(len);
+                    reserve *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        msg.written();
+        Ok(())
     }
 }
 
@@ -45,6 +68,113 @@ impl Decoder for Codec {
 }
 
 
+/// A sink which buffers outgoing messages until flushed.
+pub trait Buffered {
+    /// Return the number of bytes which are buffered but not written yet.
+    fn buffered(&self) -> usize;
+}
+
+
+/// A sink which doesn't expose the length of its buffer, regarded as always empty.
+///
+/// This is used for message-oriented transports, which buffer messages by themselves.
+pub struct Unbuffered<S>(pub S);
+
+impl<S> Buffered for Unbuffered<S> {
+    fn buffered(&self) -> usize {
+        0
+    }
+}
+
+impl<S: Sink> Sink for Unbuffered<S> {
+    type SinkItem = S::SinkItem;
+    type SinkError = S::SinkError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.0.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.close()
+    }
+}
+
+
+/// A sink of `EncoderMessage`, which writes encoded messages to an asynchronous I/O.
+///
+/// Unlike `FramedWrite`, the length of its write buffer is exposed via `Buffered`, so that the
+/// flush policy can be decided by the owner.
+pub struct MessageWriter<W: AsyncWrite> {
+    inner: W,
+    buffer: BytesMut,
+}
+
+/// The length of write buffer, above which `start_send` tries to flush the buffer first.
+const HIGH_WATER_MARK: usize = 64 * 1024;
+
+impl<W: AsyncWrite> MessageWriter<W> {
+    pub fn new(inner: W) -> Self {
+        MessageWriter {
+            inner,
+            buffer: BytesMut::new(),
+        }
+    }
+}
+
+impl<W: AsyncWrite> Buffered for MessageWriter<W> {
+    fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl<W: AsyncWrite> Sink for MessageWriter<W> {
+    type SinkItem = EncoderMessage;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.buffer.len() >= HIGH_WATER_MARK {
+            self.poll_complete()?;
+            if self.buffer.len() >= HIGH_WATER_MARK {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+        Codec.encode(item, &mut self.buffer)?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        while !self.buffer.is_empty() {
+            let n = match self.inner.write(&self.buffer) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write messages to the I/O",
+                    ))
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            };
+            self.buffer.split_to(n);
+        }
+        match self.inner.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_complete());
+        self.inner.shutdown()
+    }
+}
+
+
 #[derive(Debug)]
 pub enum EncoderMessage {
     Request(u64, Request),
@@ -55,18 +185,24 @@ pub enum EncoderMessage {
 impl EncoderMessage {
     /// Write a response to an output stream, with given ID.
     pub fn into_writer<W: Write>(self, w: &mut W) -> io::Result<()> {
-        let packet = match self {
+        write_packet(w, &self.to_packet())?;
+        self.written();
+        Ok(())
+    }
+
+    fn to_packet(&self) -> Value {
+        match *self {
             EncoderMessage::Request(id, ref req) => req.to_packet(id),
             EncoderMessage::Response(id, ref res) => res.to_packet(id),
             EncoderMessage::Notification(ref not, _) => not.to_packet(),
-        };
-        write_packet(w, &packet)?;
+        }
+    }
 
+    /// Notify the completion of writing, if the message is a notification.
+    fn written(self) {
         if let EncoderMessage::Notification(_, done) = self {
             let _ = done.send(());
         }
-
-        Ok(())
     }
 }
 
@@ -82,6 +218,11 @@ impl DecoderMessage {
     /// Read a request and its ID from an input stream
     pub fn from_reader<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
         let value = next_value(r)?;
+        Self::from_value(&value)
+    }
+
+    /// Convert a decoded packet into a message.
+    pub fn from_value(value: &Value) -> Result<Self, DecodeError> {
         let array = value.as_array().ok_or(DecodeError::Invalid)?;
         match (array.get(0).and_then(|v| v.as_i64()), array.len()) {
             (Some(REQUEST_TYPE), n) if n >= 4 => Request::from_array(&array[1..4]),
@@ -93,6 +234,36 @@ impl DecoderMessage {
 }
 
 
+impl<'a> Message<'a> {
+    /// Return the packet of the message, as written to the wire.
+    pub(crate) fn to_packet(&self) -> Value {
+        match *self {
+            Message::Request {
+                msgid,
+                method,
+                params,
+            } => Value::Array(vec![
+                REQUEST_TYPE.into(),
+                msgid.into(),
+                method.into(),
+                params.clone(),
+            ]),
+            Message::Response { msgid, result } => Value::Array(vec![
+                RESPONSE_TYPE.into(),
+                msgid.into(),
+                result.err().cloned().unwrap_or(Value::Nil),
+                result.ok().cloned().unwrap_or(Value::Nil),
+            ]),
+            Message::Notification { method, params } => Value::Array(vec![
+                NOTIFICATION_TYPE.into(),
+                method.into(),
+                params.clone(),
+            ]),
+        }
+    }
+}
+
+
 /// A request message
 #[derive(Debug)]
 pub struct Request {
@@ -165,15 +336,21 @@ impl Response {
         self.0
     }
 
+    /// Return the references of the result or the error value.
+    pub fn as_result(&self) -> Result<&Value, &Value> {
+        self.0.as_ref()
+    }
+
     fn from_array(array: &[Value]) -> Result<DecoderMessage, DecodeError> {
+        // The packet is `[type, msgid, error, result]`, where `error` is nil on success.
         match (array[0].as_i64(), &array[1], &array[2]) {
-            (Some(id), val, &Value::Nil) => Ok(DecoderMessage::Response(
+            (Some(id), &Value::Nil, result) => Ok(DecoderMessage::Response(
                 id as u64,
-                Response(Ok(val.clone())),
+                Response(Ok(result.clone())),
             )),
-            (Some(id), &Value::Nil, val) => Ok(DecoderMessage::Response(
+            (Some(id), error, _) => Ok(DecoderMessage::Response(
                 id as u64,
-                Response(Err(val.clone())),
+                Response(Err(error.clone())),
             )),
             _ => return Err(DecodeError::Invalid),
         }
@@ -253,7 +430,7 @@ impl From<io::Error> for DecodeError {
 
 
 
-fn next_value<R: Read>(r: &mut R) -> Result<Value, DecodeError> {
+pub(crate) fn next_value<R: Read>(r: &mut R) -> Result<Value, DecodeError> {
     rmpv::decode::read_value(r).map_err(|err| {
         use rmpv::decode::Error::*;
         match err {
@@ -263,7 +440,7 @@ fn next_value<R: Read>(r: &mut R) -> Result<Value, DecodeError> {
     })
 }
 
-fn write_packet<W: Write>(w: &mut W, packet: &Value) -> io::Result<()> {
+pub(crate) fn write_packet<W: Write>(w: &mut W, packet: &Value) -> io::Result<()> {
     rmpv::encode::write_value(w, packet).map_err(|err| {
         use rmpv::encode::Error::*;
         match err {
@@ -0,0 +1,595 @@
+//!
+//! recording of sessions, and replaying them.
+//!
+//! A session is recorded by attaching a `Recorder` to an endpoint:
+//!
+//! ```ignore
+//! let endpoint = Endpoint::builder()
+//!     .tap(Recorder::create("session.rec")?)
+//!     .from_io(&handle, io);
+//! ```
+//!
+//! The recording can be replayed later, as a fake peer or against a handler:
+//!
+//! ```ignore
+//! let recording = Recording::open("session.rec")?;
+//!
+//! // Answer the requests from the recording, on behalf of the recorded peer.
+//! endpoint.serve(&handle, ReplayPeer::new(&recording));
+//!
+//! // Re-drive a handler with the recorded requests, and compare its responses.
+//! let mismatches = core.run(recording.replay(&handle, MyHandler::new()))?;
+//! assert!(mismatches.is_empty());
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use futures::{Future, Stream, Sink, Poll, Async};
+use futures::future::{self, FutureResult};
+use futures::sync::mpsc;
+use tokio_core::reactor::{Handle, Timeout};
+use rmpv::Value;
+
+use super::Handler;
+use super::client::Client;
+use super::context::{Connection, RequestContext};
+use super::message::{self, DecodeError, DecoderMessage, Response};
+use super::tap::{Direction, Frame, Message, Tap};
+
+
+/// A tap which records every frame into a writer, with its direction and timing.
+///
+/// Each frame is written as a MessagePack array `[direction, elapsed, packet, connection]`,
+/// where `direction` is `0` for inbound and `1` for outbound, `elapsed` is the time in
+/// microseconds since the recorder has been created, `packet` is the message as sent on the
+/// wire, and `connection` is the ID of the connection. A recorder attached to several
+/// endpoints (e.g. all connections of a server) thus keeps their sessions apart.
+///
+/// On the wire, an error response whose error is `nil` cannot be told from a success. Its
+/// packet is recorded with an extra element `true`, so that it is replayed as an error.
+pub struct Recorder<W: Write + 'static> {
+    inner: RefCell<W>,
+    start: SystemTime,
+}
+
+impl Recorder<File> {
+    /// Create a recorder which writes to the file at `path`.
+    ///
+    /// The file is truncated if it already exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Recorder::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + 'static> Recorder<W> {
+    /// Create a recorder which writes to given writer.
+    pub fn new(inner: W) -> Self {
+        Recorder {
+            inner: RefCell::new(inner),
+            start: SystemTime::now(),
+        }
+    }
+
+    /// Consume the recorder and return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
+
+impl<W: Write + 'static> Tap for Recorder<W> {
+    fn tap(&self, frame: &Frame) {
+        let direction = match frame.direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        };
+        let elapsed = frame.timestamp.duration_since(self.start).unwrap_or_default();
+        let entry = Value::Array(vec![
+            Value::from(direction),
+            Value::from(as_micros(elapsed)),
+            packet(&frame.message),
+            Value::from(frame.connection),
+        ]);
+
+        // Each entry is written at once, so that the recording is not torn by a crash.
+        let mut buf = Vec::new();
+        if message::write_packet(&mut buf, &entry).is_err() {
+            return;
+        }
+        let mut inner = self.inner.borrow_mut();
+        if inner.write_all(&buf).is_ok() {
+            let _ = inner.flush();
+        }
+    }
+}
+
+
+/// Return the packet of the message, marking an error response with a `nil` error.
+fn packet(message: &Message) -> Value {
+    let mut packet = message.to_packet();
+    if let Message::Response { result: Err(&Value::Nil), .. } = *message {
+        if let Value::Array(ref mut array) = packet {
+            array.push(Value::Boolean(true));
+        }
+    }
+    packet
+}
+
+
+/// A message in a recording.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedMessage {
+    /// A request
+    Request {
+        /// The ID of the request
+        msgid: u64,
+        /// The method name
+        method: String,
+        /// Arguments of the method
+        params: Value,
+    },
+    /// A response
+    Response {
+        /// The ID of the corresponding request
+        msgid: u64,
+        /// The result, or the error value
+        result: Result<Value, Value>,
+    },
+    /// A notification
+    Notification {
+        /// The method name
+        method: String,
+        /// Arguments of the method
+        params: Value,
+    },
+}
+
+impl From<DecoderMessage> for RecordedMessage {
+    fn from(msg: DecoderMessage) -> Self {
+        match msg {
+            DecoderMessage::Request(msgid, req) => RecordedMessage::Request {
+                msgid,
+                method: req.method,
+                params: req.params,
+            },
+            DecoderMessage::Response(msgid, res) => RecordedMessage::Response {
+                msgid,
+                result: res.into_inner(),
+            },
+            DecoderMessage::Notification(not) => RecordedMessage::Notification {
+                method: not.method,
+                params: not.params,
+            },
+        }
+    }
+}
+
+/// An entry of a recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The direction of the message, seen from the recorded endpoint
+    pub direction: Direction,
+    /// The ID of the connection of the recorded endpoint
+    pub connection: u64,
+    /// The elapsed time since the recording has started
+    pub elapsed: Duration,
+    /// The message
+    pub message: RecordedMessage,
+}
+
+
+/// A session recorded by `Recorder`.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    entries: Vec<Entry>,
+}
+
+impl Recording {
+    /// Load a recording from the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Recording::from_reader(io::BufReader::new(File::open(path)?))
+    }
+
+    /// Load a recording from a reader.
+    ///
+    /// A truncated entry at the end, left by an interrupted recorder, is ignored.
+    pub fn from_reader<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        loop {
+            let value = match message::next_value(&mut r) {
+                Ok(value) => value,
+                Err(DecodeError::Truncated) => break,
+                Err(DecodeError::Invalid) => return Err(invalid_entry()),
+                Err(DecodeError::Unknown(err)) => return Err(err),
+            };
+            entries.push(parse_entry(&value).ok_or_else(invalid_entry)?);
+        }
+        Ok(Recording { entries })
+    }
+
+    /// Return the entries of the recording, in the order of recorded.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Re-drive a handler with the requests/notifications received by the recorded endpoint,
+    /// and compare its responses with the recorded ones.
+    ///
+    /// The messages are passed to the handler one by one, in the recorded order, and the
+    /// responses are compared with those of the same connection. Each recorded connection is
+    /// replayed on its own `Connection`, which is closed and torn down at the end. Requests
+    /// sent from the handler via `Client` are answered from the recording, as `ReplayPeer`
+    /// does. The returned future resolves to the list of responses which differ from the
+    /// recording.
+    pub fn replay<H: Handler>(&self, handle: &Handle, handler: H) -> Replay<H> {
+        let (tx_req, rx_req) = mpsc::channel(1);
+        let (tx_res, rx_res) = mpsc::channel(1);
+        let (tx_not, rx_not) = mpsc::channel(1);
+        let client = Client::new(handle, tx_req, rx_res, tx_not);
+
+        let answers = Answers::new(self);
+        handle.spawn(rx_req.for_each(move |(id, req)| {
+            let res = answers
+                .take(&req.method, &req.params)
+                .map(|(result, _)| result)
+                .unwrap_or_else(|| Err(not_recorded(&req.method)));
+            tx_res.clone().send((id, Response::from(res))).then(|_| Ok(()))
+        }));
+        handle.spawn(rx_not.for_each(|(_, done)| {
+            let _ = done.send(());
+            Ok(())
+        }));
+
+        let mut expected = HashMap::new();
+        let mut incoming = VecDeque::new();
+        for entry in &self.entries {
+            match (entry.direction, &entry.message) {
+                (Direction::Outbound, &RecordedMessage::Response { msgid, ref result }) => {
+                    expected.insert((entry.connection, msgid), result.clone());
+                }
+                (Direction::Inbound, &RecordedMessage::Request { .. }) |
+                (Direction::Inbound, &RecordedMessage::Notification { .. }) => {
+                    incoming.push_back((entry.connection, entry.message.clone()));
+                }
+                _ => (),
+            }
+        }
+
+        Replay {
+            handler,
+            client,
+            connections: HashMap::new(),
+            incoming,
+            expected,
+            current: None,
+            mismatches: Vec::new(),
+        }
+    }
+}
+
+fn parse_entry(value: &Value) -> Option<Entry> {
+    let array = value.as_array()?;
+    if array.len() != 4 {
+        return None;
+    }
+    let connection = array[3].as_u64()?;
+    let direction = match array[0].as_u64()? {
+        0 => Direction::Inbound,
+        1 => Direction::Outbound,
+        _ => return None,
+    };
+    let micros = array[1].as_u64()?;
+    let mut message = RecordedMessage::from(DecoderMessage::from_value(&array[2]).ok()?);
+    if let RecordedMessage::Response { ref mut result, .. } = message {
+        let nil_error = array[2].as_array().and_then(|packet| packet.get(4)).and_then(
+            |flag| flag.as_bool(),
+        );
+        if nil_error == Some(true) {
+            *result = Err(Value::Nil);
+        }
+    }
+    Some(Entry {
+        direction,
+        connection,
+        elapsed: Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000),
+        message,
+    })
+}
+
+fn invalid_entry() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid entry in the recording")
+}
+
+fn not_recorded(method: &str) -> Value {
+    format!("The request is not found in the recording: {:?}", method).into()
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+
+/// Responses of the peer in a recording, indexed by their requests.
+struct Answers(RefCell<Vec<Answer>>);
+
+struct Answer {
+    method: String,
+    params: Value,
+    result: Result<Value, Value>,
+    latency: Duration,
+    used: bool,
+}
+
+impl Answers {
+    /// Collect the requests sent from the recorded endpoint, and the responses to them.
+    fn new(recording: &Recording) -> Self {
+        let mut requests = HashMap::new();
+        let mut answers = Vec::new();
+        for entry in &recording.entries {
+            match (entry.direction, &entry.message) {
+                (Direction::Outbound, &RecordedMessage::Request {
+                     msgid,
+                     ref method,
+                     ref params,
+                 }) => {
+                    requests.insert(
+                        (entry.connection, msgid),
+                        (method.clone(), params.clone(), entry.elapsed),
+                    );
+                }
+                (Direction::Inbound, &RecordedMessage::Response { msgid, ref result }) => {
+                    if let Some((method, params, sent)) =
+                        requests.remove(&(entry.connection, msgid))
+                    {
+                        answers.push(Answer {
+                            method,
+                            params,
+                            result: result.clone(),
+                            latency: entry.elapsed.checked_sub(sent).unwrap_or_default(),
+                            used: false,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+        Answers(RefCell::new(answers))
+    }
+
+    /// Take the first unused response to the request with the same method and parameters.
+    fn take(&self, method: &str, params: &Value) -> Option<(Result<Value, Value>, Duration)> {
+        let mut answers = self.0.borrow_mut();
+        answers
+            .iter_mut()
+            .find(|a| !a.used && a.method == method && a.params == *params)
+            .map(|a| {
+                a.used = true;
+                (a.result.clone(), a.latency)
+            })
+    }
+}
+
+
+/// A handler which impersonates the peer of a recorded endpoint.
+///
+/// It answers each request with the response of the recorded peer to the request with the
+/// same method and parameters. Identical requests are answered in the recorded order.
+/// Notifications are ignored.
+pub struct ReplayPeer {
+    answers: Answers,
+    handle: Option<Handle>,
+}
+
+impl ReplayPeer {
+    /// Create a fake peer from a recording.
+    pub fn new(recording: &Recording) -> Self {
+        ReplayPeer {
+            answers: Answers::new(recording),
+            handle: None,
+        }
+    }
+
+    /// Delay each response by the latency of the recorded peer.
+    pub fn realtime(mut self, handle: &Handle) -> Self {
+        self.handle = Some(handle.clone());
+        self
+    }
+}
+
+impl Handler for ReplayPeer {
+    type RequestFuture = Box<Future<Item = Value, Error = Value>>;
+    type NotifyFuture = FutureResult<(), ()>;
+
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::RequestFuture {
+        let (result, latency) = match self.answers.take(method, &params) {
+            Some(answer) => answer,
+            None => return Box::new(future::err(not_recorded(method))),
+        };
+        match self.handle {
+            Some(ref handle) => {
+                match Timeout::new(latency, handle) {
+                    Ok(timeout) => Box::new(timeout.then(move |_| result)),
+                    Err(err) => Box::new(future::err(err.to_string().into())),
+                }
+            }
+            None => Box::new(future::result(result)),
+        }
+    }
+
+    fn handle_notification(
+        &self,
+        _: &str,
+        _: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::NotifyFuture {
+        future::ok(())
+    }
+}
+
+
+/// A response of the handler which differs from the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// The ID of the connection which has received the request
+    pub connection: u64,
+    /// The ID of the recorded request
+    pub msgid: u64,
+    /// The method name
+    pub method: String,
+    /// Arguments of the method
+    pub params: Value,
+    /// The recorded response, or `None` if the request had not been answered
+    pub expected: Option<Result<Value, Value>>,
+    /// The response of the handler
+    pub actual: Result<Value, Value>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "request #{} {:?} {}: expected {:?}, actual {:?}",
+            self.msgid,
+            self.method,
+            self.params,
+            self.expected,
+            self.actual
+        )
+    }
+}
+
+
+/// A future to replay a recording against a handler, created by `Recording::replay()`.
+pub struct Replay<H: Handler> {
+    handler: H,
+    client: Client,
+    connections: HashMap<u64, Connection>,
+    incoming: VecDeque<(u64, RecordedMessage)>,
+    expected: HashMap<(u64, u64), Result<Value, Value>>,
+    current: Option<Pending<H>>,
+    mismatches: Vec<Mismatch>,
+}
+
+enum Pending<H: Handler> {
+    Request((u64, u64), String, Value, H::RequestFuture),
+    Notification(H::NotifyFuture),
+}
+
+impl<H: Handler> Replay<H> {
+    /// Return the connection replaying the recorded one, which is created on its first message.
+    fn connection(&mut self, id: u64) -> Connection {
+        if let Some(connection) = self.connections.get(&id) {
+            return connection.clone();
+        }
+        let connection = Connection::new();
+        self.handler.on_connect(&connection, &self.client);
+        self.connections.insert(id, connection.clone());
+        connection
+    }
+
+    /// Close every connection, and tear down the handler of each of them.
+    fn finish(&mut self) {
+        let mut connections: Vec<_> = self.connections.drain().collect();
+        connections.sort_by_key(|&(id, _)| id);
+        for (_, connection) in connections {
+            connection.close(None);
+            self.handler.on_disconnect(&connection, None);
+            self.handler.on_teardown(&connection);
+        }
+    }
+
+    fn start(&mut self, id: u64, msg: RecordedMessage) -> Option<Pending<H>> {
+        let connection = self.connection(id);
+        match msg {
+            RecordedMessage::Request {
+                msgid,
+                method,
+                params,
+            } => {
+                let ctx = RequestContext::new(Some(msgid), None, connection);
+                let future = self.handler.handle_request(
+                    &method,
+                    params.clone(),
+                    &self.client,
+                    &ctx,
+                );
+                Some(Pending::Request((id, msgid), method, params, future))
+            }
+            RecordedMessage::Notification { method, params } => {
+                let ctx = RequestContext::new(None, None, connection);
+                let future = self.handler.handle_notification(
+                    &method,
+                    params,
+                    &self.client,
+                    &ctx,
+                );
+                Some(Pending::Notification(future))
+            }
+            RecordedMessage::Response { .. } => None,
+        }
+    }
+}
+
+impl<H: Handler> Future for Replay<H> {
+    type Item = Vec<Mismatch>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if self.current.is_none() {
+                match self.incoming.pop_front() {
+                    Some((connection, msg)) => self.current = self.start(connection, msg),
+                    None => {
+                        self.finish();
+                        let mismatches = ::std::mem::take(&mut self.mismatches);
+                        return Ok(Async::Ready(mismatches));
+                    }
+                }
+                continue;
+            }
+
+            let mismatch = match self.current {
+                Some(Pending::Request(key, ref method, ref params, ref mut future)) => {
+                    let actual = match future.poll() {
+                        Ok(Async::Ready(value)) => Ok(value),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(value) => Err(value),
+                    };
+                    let expected = self.expected.remove(&key);
+                    if expected.as_ref() == Some(&actual) {
+                        None
+                    } else {
+                        Some(Mismatch {
+                            connection: key.0,
+                            msgid: key.1,
+                            method: method.clone(),
+                            params: params.clone(),
+                            expected,
+                            actual,
+                        })
+                    }
+                }
+                Some(Pending::Notification(ref mut future)) => {
+                    match future.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        _ => None,
+                    }
+                }
+                None => unreachable!(),
+            };
+            self.mismatches.extend(mismatch);
+            self.current = None;
+        }
+    }
+}
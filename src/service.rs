@@ -0,0 +1,346 @@
+//!
+//! definition of the `rpc_service!` macro.
+//!
+
+/// Define a typed service shared by both peers of a connection.
+///
+/// The macro generates three items from a single definition:
+///
+/// * a trait which the server side implements,
+/// * a client wrapper over `Client`, which exposes a typed method per request/notification,
+/// * a server adapter which implements `Handler` by dispatching to the trait.
+///
+/// Since both sides are generated from the same definition, method names and argument shapes
+/// cannot drift between peers. Arguments and return values are converted with Serde, so this
+/// macro requires the feature `with-serde`.
+///
+/// Requests with an unknown method or invalid arguments receive an error response, and such
+/// notifications fail, which is reported through `Endpoint::on_error()`.
+///
+/// # Example
+///
+/// ```ignore
+/// rpc_service! {
+///     /// A calculator service.
+///     pub trait Calc {
+///         client: CalcClient,
+///         server: CalcServer,
+///
+///         requests {
+///             /// Return the sum of two integers.
+///             fn add(a: i64, b: i64) -> i64;
+///         }
+///
+///         notifications {
+///             fn log(message: String);
+///         }
+///     }
+/// }
+///
+/// struct CalcImpl;
+///
+/// impl Calc for CalcImpl {
+///     fn add(
+///         &self,
+///         a: i64,
+///         b: i64,
+///         _: &Client,
+///         _: &RequestContext,
+///     ) -> Box<Future<Item = i64, Error = Value>> {
+///         Box::new(ok(a + b))
+///     }
+///
+///     fn log(
+///         &self,
+///         message: String,
+///         _: &Client,
+///         _: &RequestContext,
+///     ) -> Box<Future<Item = (), Error = ()>> {
+///         eprintln!("{}", message);
+///         Box::new(ok(()))
+///     }
+/// }
+///
+/// // Serve the service on an endpoint.
+/// endpoint.serve(&handle, CalcServer(CalcImpl));
+///
+/// // Call the service from the other peer.
+/// let calc = CalcClient::new(endpoint.into_client());
+/// let task = calc.add(1, 2).and_then(|res| {
+///     eprintln!("{:?}", res);
+///     Ok(())
+/// });
+/// ```
+#[macro_export]
+macro_rules! rpc_service {
+    (
+        $(#[$attr:meta])*
+        pub trait $name:ident {
+            client: $client:ident,
+            server: $server:ident,
+
+            requests {
+                $(
+                    $(#[$req_attr:meta])*
+                    fn $req:ident ( $( $req_arg:ident : $req_ty:ty ),* ) -> $req_ret:ty ;
+                )*
+            }
+
+            notifications {
+                $(
+                    $(#[$not_attr:meta])*
+                    fn $not:ident ( $( $not_arg:ident : $not_ty:ty ),* ) ;
+                )*
+            }
+        }
+    ) => {
+        $(#[$attr])*
+        pub trait $name: 'static {
+            $(
+                $(#[$req_attr])*
+                fn $req(
+                    &self,
+                    $( $req_arg: $req_ty, )*
+                    _client: &$crate::Client,
+                    _ctx: &$crate::RequestContext
+                ) -> Box<$crate::__rt::Future<Item = $req_ret, Error = $crate::Value>>;
+            )*
+
+            $(
+                $(#[$not_attr])*
+                fn $not(
+                    &self,
+                    $( $not_arg: $not_ty, )*
+                    _client: &$crate::Client,
+                    _ctx: &$crate::RequestContext
+                ) -> Box<$crate::__rt::Future<Item = (), Error = ()>>;
+            )*
+        }
+
+        /// A typed client, generated by `rpc_service!`.
+        #[derive(Clone)]
+        pub struct $client($crate::Client);
+
+        impl $client {
+            /// Wrap a `Client` associated with the peer.
+            pub fn new(client: $crate::Client) -> Self {
+                $client(client)
+            }
+
+            /// Return the reference of underlying `Client`.
+            pub fn client(&self) -> &$crate::Client {
+                &self.0
+            }
+
+            $(
+                $(#[$req_attr])*
+                pub fn $req(
+                    &self,
+                    $( $req_arg: $req_ty ),*
+                ) -> Box<$crate::__rt::Future<
+                    Item = Result<$req_ret, $crate::Value>,
+                    Error = ::std::io::Error,
+                >> {
+                    $crate::__rt::request(
+                        &self.0,
+                        stringify!($req),
+                        vec![ $( $crate::__rt::to_value($req_arg) ),* ],
+                    )
+                }
+            )*
+
+            $(
+                $(#[$not_attr])*
+                pub fn $not(
+                    &self,
+                    $( $not_arg: $not_ty ),*
+                ) -> Box<$crate::__rt::Future<Item = (), Error = ::std::io::Error>> {
+                    $crate::__rt::notify(
+                        &self.0,
+                        stringify!($not),
+                        vec![ $( $crate::__rt::to_value($not_arg) ),* ],
+                    )
+                }
+            )*
+        }
+
+        /// A server adapter which implements `Handler`, generated by `rpc_service!`.
+        pub struct $server<T: $name>(pub T);
+
+        impl<T: $name> $crate::Handler for $server<T> {
+            type RequestFuture = Box<$crate::__rt::Future<Item = $crate::Value, Error = $crate::Value>>;
+            type NotifyFuture = Box<$crate::__rt::Future<Item = (), Error = ()>>;
+
+            #[allow(unused_mut, unused_variables)]
+            fn handle_request(
+                &self,
+                __method: &str,
+                __params: $crate::Value,
+                __client: &$crate::Client,
+                __ctx: &$crate::RequestContext,
+            ) -> Self::RequestFuture {
+                let mut __params = match $crate::__rt::Params::new(__params) {
+                    Ok(params) => params,
+                    Err(err) => return $crate::__rt::reject(err),
+                };
+                $(
+                    if __method == stringify!($req) {
+                        $(
+                            let $req_arg: $req_ty = match __params.next(stringify!($req_arg)) {
+                                Ok(arg) => arg,
+                                Err(err) => return $crate::__rt::reject(err),
+                            };
+                        )*
+                        if let Err(err) = __params.finish() {
+                            return $crate::__rt::reject(err);
+                        }
+                        return $crate::__rt::respond(
+                            self.0.$req( $( $req_arg, )* __client, __ctx ),
+                        );
+                    }
+                )*
+                $crate::__rt::reject(format!("The method is not found: {:?}", __method).into())
+            }
+
+            #[allow(unused_mut, unused_variables)]
+            fn handle_notification(
+                &self,
+                __method: &str,
+                __params: $crate::Value,
+                __client: &$crate::Client,
+                __ctx: &$crate::RequestContext,
+            ) -> Self::NotifyFuture {
+                let mut __params = match $crate::__rt::Params::new(__params) {
+                    Ok(params) => params,
+                    Err(err) => return $crate::__rt::discard(__method, err),
+                };
+                $(
+                    if __method == stringify!($not) {
+                        $(
+                            let $not_arg: $not_ty = match __params.next(stringify!($not_arg)) {
+                                Ok(arg) => arg,
+                                Err(err) => return $crate::__rt::discard(__method, err),
+                            };
+                        )*
+                        if let Err(err) = __params.finish() {
+                            return $crate::__rt::discard(__method, err);
+                        }
+                        return self.0.$not( $( $not_arg, )* __client, __ctx );
+                    }
+                )*
+                $crate::__rt::discard(
+                    __method,
+                    format!("The method is not found: {:?}", __method).into(),
+                )
+            }
+        }
+    };
+}
+
+
+/// Helpers referenced from the expansion of `rpc_service!`.
+#[doc(hidden)]
+pub mod rt {
+    use std::io;
+    use std::vec;
+    use futures::future::err;
+    use rmpv::Value;
+    use rmpv::ext::{self, from_value};
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use super::super::Client;
+    use super::super::util::io_error;
+
+    pub use futures::Future;
+
+    pub fn to_value<T: Serialize>(value: T) -> Result<Value, ext::Error> {
+        ext::to_value(value)
+    }
+
+    pub fn request<T: DeserializeOwned + 'static>(
+        client: &Client,
+        method: &str,
+        params: Vec<Result<Value, ext::Error>>,
+    ) -> Box<Future<Item = Result<T, Value>, Error = io::Error>> {
+        let params = match params.into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(params) => params,
+            Err(e) => return Box::new(err(io_error(e.to_string()))),
+        };
+        Box::new(client.request(method, params).and_then(|res| match res {
+            Ok(value) => from_value(value).map(Ok).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            }),
+            Err(value) => Ok(Err(value)),
+        }))
+    }
+
+    pub fn notify(
+        client: &Client,
+        method: &str,
+        params: Vec<Result<Value, ext::Error>>,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        match params.into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(params) => Box::new(client.notify(method, params)),
+            Err(e) => Box::new(err(io_error(e.to_string()))),
+        }
+    }
+
+    pub fn respond<T: Serialize + 'static>(
+        f: Box<Future<Item = T, Error = Value>>,
+    ) -> Box<Future<Item = Value, Error = Value>> {
+        Box::new(f.and_then(|ret| {
+            ext::to_value(ret).map_err(|e| e.to_string().into())
+        }))
+    }
+
+    pub fn reject(value: Value) -> Box<Future<Item = Value, Error = Value>> {
+        Box::new(err(value))
+    }
+
+    /// Fail a notification which cannot be passed to the handler.
+    pub fn discard(method: &str, reason: Value) -> Box<Future<Item = (), Error = ()>> {
+        debug!("discarded the notification {:?}: {}", method, reason);
+        Box::new(err(()))
+    }
+
+    /// Positional arguments of an incoming request/notification.
+    pub struct Params {
+        args: vec::IntoIter<Value>,
+        taken: usize,
+    }
+
+    impl Params {
+        pub fn new(params: Value) -> Result<Self, Value> {
+            match params {
+                Value::Array(params) => Ok(Params {
+                    args: params.into_iter(),
+                    taken: 0,
+                }),
+                _ => Err("The parameters must be an array".into()),
+            }
+        }
+
+        pub fn next<T: DeserializeOwned>(&mut self, name: &str) -> Result<T, Value> {
+            // A missing argument is treated as `nil`, so that `Option<T>` can be omitted.
+            let value = self.args.next().unwrap_or(Value::Nil);
+            self.taken += 1;
+            from_value(value).map_err(|e| {
+                format!("The argument `{}` is invalid: {}", name, e).into()
+            })
+        }
+
+        /// Check that no extra arguments are left.
+        pub fn finish(self) -> Result<(), Value> {
+            match self.args.len() {
+                0 => Ok(()),
+                n => Err(
+                    format!(
+                        "Too many arguments: expected {}, given {}",
+                        self.taken,
+                        self.taken + n
+                    ).into(),
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//!
+//! definition of wire taps, which observe every frame sent and received.
+//!
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::LogLevel;
+use rmpv::Value;
+use super::message::{EncoderMessage, DecoderMessage};
+
+
+/// The direction of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The frame is received from the peer.
+    Inbound,
+    /// The frame is sent to the peer.
+    Outbound,
+}
+
+/// A message on the wire.
+#[derive(Debug, Clone, Copy)]
+pub enum Message<'a> {
+    /// A request
+    Request {
+        /// The ID of the request
+        msgid: u64,
+        /// The method name
+        method: &'a str,
+        /// Arguments of the method
+        params: &'a Value,
+    },
+    /// A response
+    Response {
+        /// The ID of the corresponding request
+        msgid: u64,
+        /// The result, or the error value
+        result: Result<&'a Value, &'a Value>,
+    },
+    /// A notification
+    Notification {
+        /// The method name
+        method: &'a str,
+        /// Arguments of the method
+        params: &'a Value,
+    },
+}
+
+impl<'a> From<&'a DecoderMessage> for Message<'a> {
+    fn from(msg: &'a DecoderMessage) -> Self {
+        match *msg {
+            DecoderMessage::Request(msgid, ref req) => Message::Request {
+                msgid,
+                method: &req.method,
+                params: &req.params,
+            },
+            DecoderMessage::Response(msgid, ref res) => Message::Response {
+                msgid,
+                result: res.as_result(),
+            },
+            DecoderMessage::Notification(ref not) => Message::Notification {
+                method: &not.method,
+                params: &not.params,
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a EncoderMessage> for Message<'a> {
+    fn from(msg: &'a EncoderMessage) -> Self {
+        match *msg {
+            EncoderMessage::Request(msgid, ref req) => Message::Request {
+                msgid,
+                method: &req.method,
+                params: &req.params,
+            },
+            EncoderMessage::Response(msgid, ref res) => Message::Response {
+                msgid,
+                result: res.as_result(),
+            },
+            EncoderMessage::Notification(ref not, _) => Message::Notification {
+                method: &not.method,
+                params: &not.params,
+            },
+        }
+    }
+}
+
+
+/// A frame observed by a `Tap`.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    /// The direction of the frame
+    pub direction: Direction,
+    /// The ID of the connection, which tells apart the frames of endpoints sharing a tap
+    pub connection: u64,
+    /// The time when the frame is decoded, or queued to be written
+    pub timestamp: SystemTime,
+    /// The message
+    pub message: Message<'a>,
+}
+
+impl<'a> Frame<'a> {
+    pub(crate) fn new<M: Into<Message<'a>>>(
+        direction: Direction,
+        connection: u64,
+        message: M,
+    ) -> Self {
+        Frame {
+            direction,
+            connection,
+            timestamp: SystemTime::now(),
+            message: message.into(),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Frame<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "[{}.{:06}] {} ",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            match self.direction {
+                Direction::Inbound => "<--",
+                Direction::Outbound => "-->",
+            }
+        )?;
+        match self.message {
+            Message::Request {
+                msgid,
+                method,
+                params,
+            } => write!(f, "request #{} {:?} {}", msgid, method, params),
+            Message::Response {
+                msgid,
+                result: Ok(value),
+            } => write!(f, "response #{} ok {}", msgid, value),
+            Message::Response {
+                msgid,
+                result: Err(value),
+            } => write!(f, "response #{} err {}", msgid, value),
+            Message::Notification { method, params } => {
+                write!(f, "notification {:?} {}", method, params)
+            }
+        }
+    }
+}
+
+
+/// An observer of frames, attached to an endpoint by `EndpointBuilder::tap()`.
+///
+/// The tap is called synchronously from the event loop, so it should not block.
+pub trait Tap: 'static {
+    /// Called for each frame received from or sent to the peer.
+    fn tap(&self, frame: &Frame);
+}
+
+impl<F: Fn(&Frame) + 'static> Tap for F {
+    fn tap(&self, frame: &Frame) {
+        (*self)(frame)
+    }
+}
+
+
+/// A tap which emits every frame to the `log` crate, under the target `msgpack_rpc::tap`.
+#[derive(Debug, Clone, Copy)]
+pub struct LogTap {
+    level: LogLevel,
+}
+
+impl Default for LogTap {
+    fn default() -> Self {
+        LogTap { level: LogLevel::Debug }
+    }
+}
+
+impl LogTap {
+    /// Create a tap which logs frames with the level `Debug`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the level of log records.
+    pub fn level(mut self, level: LogLevel) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+impl Tap for LogTap {
+    fn tap(&self, frame: &Frame) {
+        log!(target: "msgpack_rpc::tap", self.level, "{}", frame);
+    }
+}
+
+
+/// A tap which pretty-prints every frame to a writer, one line per frame.
+pub struct WriterTap<W: Write + 'static> {
+    inner: RefCell<W>,
+}
+
+impl WriterTap<io::Stderr> {
+    /// Create a tap which prints frames to the standard error.
+    pub fn stderr() -> Self {
+        WriterTap::new(io::stderr())
+    }
+}
+
+impl WriterTap<File> {
+    /// Create a tap which appends frames to the file at `path`.
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WriterTap::new(file))
+    }
+}
+
+impl<W: Write + 'static> WriterTap<W> {
+    /// Create a tap which prints frames to given writer.
+    pub fn new(inner: W) -> Self {
+        WriterTap { inner: RefCell::new(inner) }
+    }
+
+    /// Consume the tap and return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
+
+impl<W: Write + 'static> Tap for WriterTap<W> {
+    fn tap(&self, frame: &Frame) {
+        let mut inner = self.inner.borrow_mut();
+        // A tap must not disturb the endpoint, so the failure of writes is ignored.
+        if writeln!(inner, "{}", frame).is_err() {
+            return;
+        }
+        let _ = inner.flush();
+    }
+}
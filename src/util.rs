@@ -1,6 +1,83 @@
+use std::cell::RefCell;
 use std::error;
 use std::io;
+use std::time::Duration;
+use futures::{Future, Poll, Async};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
 
 pub fn io_error<E: Into<Box<error::Error + Send + Sync>>>(err: E) -> io::Error {
     io::Error::new(io::ErrorKind::Other, err)
 }
+
+/// A set of tasks waiting for a change of shared state in the same thread.
+#[derive(Default)]
+pub struct Waiters(RefCell<Vec<Task>>);
+
+impl Waiters {
+    /// Register the current task, to be notified at the next call of `notify_all()`.
+    pub fn register(&self) {
+        let mut tasks = self.0.borrow_mut();
+        if !tasks.iter().any(|t| t.will_notify_current()) {
+            tasks.push(task::current());
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let tasks = ::std::mem::take(&mut *self.0.borrow_mut());
+        for task in tasks {
+            task.notify();
+        }
+    }
+}
+
+
+/// A future which fails with `io::ErrorKind::TimedOut` unless the inner future is resolved
+/// within the timeout, measured from the first poll.
+pub struct Deadline<F> {
+    inner: F,
+    handle: Handle,
+    timeout: Option<Duration>,
+    timer: Option<Timeout>,
+    message: &'static str,
+}
+
+impl<F: Future<Error = io::Error>> Deadline<F> {
+    /// Wrap the future without a timeout, with the message of the timeout error.
+    pub fn new(inner: F, handle: &Handle, message: &'static str) -> Self {
+        Deadline {
+            inner,
+            handle: handle.clone(),
+            timeout: None,
+            timer: None,
+            message,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<F: Future<Error = io::Error>> Future for Deadline<F> {
+    type Item = F::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(timeout) = self.timeout.take() {
+            self.timer = Some(Timeout::new(timeout, &self.handle)?);
+        }
+
+        if let Async::Ready(item) = self.inner.poll()? {
+            return Ok(Async::Ready(item));
+        }
+
+        if let Some(ref mut timer) = self.timer {
+            if let Async::Ready(()) = timer.poll()? {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, self.message));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
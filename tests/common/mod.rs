@@ -0,0 +1,30 @@
+//! Handlers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use msgpack_rpc::{handler_fn, Client, Connection, HandlerFn, RequestContext, Value};
+
+/// A function which responds to a request.
+pub type Respond = fn(&str, Value, &Client, &RequestContext) -> Result<Value, Value>;
+
+/// A function which handles a notification.
+pub type Notify = fn(&str, Value, &Client, &RequestContext) -> Result<(), ()>;
+
+/// A handler made of plain functions, which can be named in the type of a handler factory.
+pub type FnHandler = HandlerFn<Respond, Notify>;
+
+/// Create a handler which responds to requests with `request`, and ignores notifications.
+pub fn responder(request: Respond) -> FnHandler {
+    fn notify(_: &str, _: Value, _: &Client, _: &RequestContext) -> Result<(), ()> {
+        Ok(())
+    }
+    handler_fn(request, notify)
+}
+
+/// A handler factory whose handlers respond to every request with `"pong"`.
+pub fn pong(_: &Connection, _: &Client) -> FnHandler {
+    fn request(_: &str, _: Value, _: &Client, _: &RequestContext) -> Result<Value, Value> {
+        Ok(Value::from("pong"))
+    }
+    responder(request)
+}
@@ -0,0 +1,152 @@
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+use futures::Future;
+use futures::future::Either;
+use msgpack_rpc::{handler_fn, Client, Connection, Endpoint, RequestContext, Value};
+use msgpack_rpc::io::Server;
+use tokio_core::reactor::{Core, Timeout};
+
+fn echo(_: &str, params: Value, _: &Client, _: &RequestContext) -> Result<Value, Value> {
+    Ok(params)
+}
+
+fn ignore(_: &str, _: Value, _: &Client, _: &RequestContext) -> Result<(), ()> {
+    Ok(())
+}
+
+/// Return a UDP address which no socket is bound to.
+fn unused_udp_addr() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+/// Send a request to echo `n`, and return the response unless it takes longer than the
+/// duration.
+fn echo_within(core: &mut Core, client: &Client, n: u64, timeout: Duration) -> Option<Value> {
+    let res = client.request("echo", vec![Value::from(n)]);
+    let timer = Timeout::new(timeout, &core.handle()).unwrap();
+    match core.run(res.select2(timer)) {
+        Ok(Either::A((res, _))) => Some(res.unwrap()),
+        Ok(Either::B(_)) => None,
+        Err(_) => panic!("the request has failed"),
+    }
+}
+
+#[test]
+fn round_trip_over_udp() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = unused_udp_addr();
+    let (serve, _) = Server::new(|_: &Connection, _: &Client| handler_fn(echo, ignore))
+        .serve_udp(&handle, &addr.to_string())
+        .unwrap();
+    handle.spawn(serve.map_err(|err| panic!("{}", err)));
+
+    let client = Endpoint::connect_udp(&handle, &addr).unwrap().into_client();
+    for n in 0..3 {
+        let res = echo_within(&mut core, &client, n, Duration::from_secs(1));
+        assert_eq!(res, Some(Value::Array(vec![Value::from(n)])));
+    }
+}
+
+#[test]
+fn peers_over_the_limit_are_served_once_others_expire() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = unused_udp_addr();
+    let (serve, _) = Server::new(|_: &Connection, _: &Client| handler_fn(echo, ignore))
+        .max_peers(1)
+        .peer_idle_timeout(Duration::from_millis(100))
+        .serve_udp(&handle, &addr.to_string())
+        .unwrap();
+    handle.spawn(serve.map_err(|err| panic!("{}", err)));
+
+    let first = Endpoint::connect_udp(&handle, &addr).unwrap().into_client();
+    let second = Endpoint::connect_udp(&handle, &addr).unwrap().into_client();
+    let timeout = Duration::from_millis(300);
+    assert!(echo_within(&mut core, &first, 1, timeout).is_some());
+
+    // the datagram of the second peer is dropped while the first one is tracked.
+    assert_eq!(echo_within(&mut core, &second, 2, timeout), None);
+
+    // the first peer has been idle for longer than the timeout, and is forgotten.
+    let res = echo_within(&mut core, &second, 3, timeout);
+    assert_eq!(res, Some(Value::Array(vec![Value::from(3)])));
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::Duration;
+    use futures::Future;
+    use msgpack_rpc::{handler_fn, Client, Connection, Endpoint, Value};
+    use msgpack_rpc::io::{Server, UnixBind};
+    use tokio_core::reactor::Core;
+    use super::{echo, echo_within, ignore};
+
+    /// A temporary directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("msgpack-rpc-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn round_trip_over_unix_datagram() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let dir = TempDir::new("datagram");
+        let path = dir.0.join("server.sock");
+        let (serve, _) = Server::new(|_: &Connection, _: &Client| handler_fn(echo, ignore))
+            .serve_unix_datagram(&handle, UnixBind::new(&path).mode(0o600))
+            .unwrap();
+        handle.spawn(serve.map_err(|err| panic!("{}", err)));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let client = Endpoint::connect_unix_datagram(&handle, dir.0.join("client.sock"), &path)
+            .unwrap()
+            .into_client();
+        let res = echo_within(&mut core, &client, 1, Duration::from_secs(1));
+        assert_eq!(res, Some(Value::Array(vec![Value::from(1)])));
+    }
+
+    #[test]
+    fn unix_datagram_socket_files_are_cleaned_up() {
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dir = TempDir::new("datagram-cleanup");
+        let path = dir.0.join("server.sock");
+
+        // a socket file left by a dead process is replaced.
+        drop(UnixDatagram::bind(&path).unwrap());
+        let server = Server::new(|_: &Connection, _: &Client| handler_fn(echo, ignore))
+            .serve_unix_datagram(&handle, path.as_path())
+            .unwrap();
+        assert!(path.exists());
+
+        // the socket file is removed with the server.
+        drop(server);
+        assert!(!path.exists());
+    }
+}
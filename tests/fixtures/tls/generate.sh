@@ -0,0 +1,35 @@
+#!/bin/sh
+# Generate the certificates used by tests/tls.rs: a CA, a server certificate for
+# `localhost` and a client certificate, all signed by the CA.
+set -e
+cd "$(dirname "$0")"
+
+cat > ca.ext <<CONF
+basicConstraints = critical, CA:TRUE
+keyUsage = critical, keyCertSign, cRLSign
+CONF
+cat > server.ext <<CONF
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth
+subjectAltName = DNS:localhost
+CONF
+cat > client.ext <<CONF
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = clientAuth
+CONF
+
+openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out ca-key.pem
+openssl req -new -key ca-key.pem -subj "/CN=msgpack-rpc test CA" -out ca.csr
+openssl x509 -req -in ca.csr -signkey ca-key.pem -sha256 -days 36500 \
+    -extfile ca.ext -out ca.pem
+
+for name in server client; do
+    openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out $name-key.pem
+    openssl req -new -key $name-key.pem -subj "/CN=$name" -out $name.csr
+    openssl x509 -req -in $name.csr -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+        -sha256 -days 36500 -extfile $name.ext -out $name.pem
+done
+
+rm -f ca-key.pem ca.srl ./*.csr ./*.ext
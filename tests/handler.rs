@@ -0,0 +1,76 @@
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+use futures::Stream;
+use futures::sync::mpsc;
+use msgpack_rpc::{handler_fn, Client, Endpoint, HandlerError, RequestContext, Value};
+use tokio_core::reactor::Core;
+
+/// Panic on the method `panic`, and respond with the method name otherwise.
+fn request(method: &str, _: Value, _: &Client, _: &RequestContext) -> Result<Value, Value> {
+    if method == "panic" {
+        panic!("oops");
+    }
+    Ok(Value::from(method))
+}
+
+fn notify(method: &str, _: Value, _: &Client, _: &RequestContext) -> Result<(), ()> {
+    if method == "panic" {
+        panic!("oops");
+    }
+    Ok(())
+}
+
+/// Create a client connected to the handlers, with the errors reported by the endpoint.
+fn setup(core: &Core) -> (Client, mpsc::UnboundedReceiver<HandlerError>) {
+    let handle = core.handle();
+    let (server, client) = Endpoint::pair(&handle);
+    let (tx, rx) = mpsc::unbounded();
+    server
+        .on_error(move |err| {
+            let _ = tx.unbounded_send(err);
+        })
+        .serve(&handle, handler_fn(request, notify));
+    (client.into_client(), rx)
+}
+
+#[test]
+fn panicked_request_gets_internal_error() {
+    let mut core = Core::new().unwrap();
+    let (client, errors) = setup(&core);
+
+    let res = core.run(client.request("panic", Vec::<Value>::new())).unwrap();
+    assert_eq!(res, Err(Value::from("Internal error: oops")));
+    let (err, _) = core.run(errors.into_future()).ok().unwrap();
+    match err {
+        Some(HandlerError::RequestPanicked { method, message, .. }) => {
+            assert_eq!(method, "panic");
+            assert_eq!(message, "oops");
+        }
+        err => panic!("unexpected error: {:?}", err),
+    }
+
+    // the endpoint keeps serving other requests.
+    let res = core.run(client.request("echo", Vec::<Value>::new())).unwrap();
+    assert_eq!(res, Ok(Value::from("echo")));
+}
+
+#[test]
+fn panicked_notification_is_reported() {
+    let mut core = Core::new().unwrap();
+    let (client, errors) = setup(&core);
+
+    core.run(client.notify("panic", Vec::<Value>::new())).unwrap();
+    let (err, _) = core.run(errors.into_future()).ok().unwrap();
+    match err {
+        Some(HandlerError::NotificationPanicked { method, message }) => {
+            assert_eq!(method, "panic");
+            assert_eq!(message, "oops");
+        }
+        err => panic!("unexpected error: {:?}", err),
+    }
+
+    let res = core.run(client.request("echo", Vec::<Value>::new())).unwrap();
+    assert_eq!(res, Ok(Value::from("echo")));
+}
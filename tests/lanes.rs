@@ -0,0 +1,210 @@
+extern crate msgpack_rpc;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::Duration;
+use futures::{Future, Poll};
+use futures::future::join_all;
+use futures::task::{self, Task};
+use msgpack_rpc::{handler_fn, Client, Endpoint, EndpointBuilder, Lane, RequestContext, Value};
+use msgpack_rpc::io::{pipe, MemoryStream, PipeOptions};
+use msgpack_rpc::tap::{Direction, Frame, Message};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const FLOOD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Seen {
+    Request,
+    Response,
+    Notification,
+}
+
+/// Send a request while the server floods the client with notifications, and return the
+/// number of notifications which arrive between the request and its response.
+///
+/// The pipe never blocks, so the notifications queued when the request arrives are written
+/// before its response.
+fn notifications_before_response(builder: EndpointBuilder) -> usize {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let s = seen.clone();
+    let builder = builder.tap(move |frame: &Frame| {
+        if frame.direction != Direction::Inbound {
+            return;
+        }
+        s.borrow_mut().push(match frame.message {
+            Message::Request { .. } => Seen::Request,
+            Message::Response { .. } => Seen::Response,
+            Message::Notification { .. } => Seen::Notification,
+        });
+    });
+    let (server, client) = builder.pair(&handle, PipeOptions::new());
+
+    let server = server.serve(
+        &handle,
+        handler_fn(
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(Value::from("pong")),
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(()),
+        ),
+    );
+    let client = client.serve(
+        &handle,
+        handler_fn(
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(Value::Nil),
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(()),
+        ),
+    );
+
+    let acks: Vec<_> = (0..FLOOD)
+        .map(|i| server.notify("flood", vec![Value::from(i)]))
+        .collect();
+    handle.spawn(join_all(acks).then(|_| Ok(())));
+
+    let res = core.run(client.request("ping", Vec::<Value>::new())).unwrap();
+    assert_eq!(res, Ok(Value::from("pong")));
+
+    let seen = seen.borrow();
+    let start = seen.iter().position(|&s| s == Seen::Request).unwrap();
+    let end = seen.iter().position(|&s| s == Seen::Response).unwrap();
+    assert!(start < end);
+    seen[start..end]
+        .iter()
+        .filter(|&&s| s == Seen::Notification)
+        .count()
+}
+
+fn assert_bounded(builder: EndpointBuilder, capacity: usize) {
+    let n = notifications_before_response(builder.notification_capacity(capacity));
+    // the queue of notifications, and a notification held by a parked sender.
+    assert!(
+        n <= capacity + 1,
+        "{} notifications arrived before the response (capacity: {})",
+        n,
+        capacity
+    );
+}
+
+#[test]
+fn response_latency_is_bounded_under_a_notification_flood() {
+    for &capacity in &[16, 256] {
+        assert_bounded(EndpointBuilder::new(), capacity);
+    }
+}
+
+/// A stream whose writes block until it is opened.
+struct Gate {
+    inner: MemoryStream,
+    open: Rc<Cell<bool>>,
+    task: Rc<RefCell<Option<Task>>>,
+}
+
+impl Read for Gate {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Gate {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.open.get() {
+            *self.task.borrow_mut() = Some(task::current());
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "closed"));
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsyncRead for Gate {}
+
+impl AsyncWrite for Gate {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[test]
+fn lane_schedule_interleaves_queued_messages() {
+    const WEIGHT: usize = 3;
+    const REQUESTS: usize = 4;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let s = sent.clone();
+    let builder = EndpointBuilder::new()
+        .request_capacity(64)
+        .notification_capacity(64)
+        .lane_priority([Lane::Notification, Lane::Request, Lane::Response])
+        .lane_weight(Lane::Notification, WEIGHT)
+        .tap(move |frame: &Frame| match (frame.direction, frame.message) {
+            (Direction::Outbound, Message::Request { .. }) => s.borrow_mut().push(Seen::Request),
+            (Direction::Outbound, Message::Notification { method, .. }) if method != "plug" => {
+                s.borrow_mut().push(Seen::Notification)
+            }
+            _ => {}
+        });
+
+    let (a, b) = pipe(&handle, PipeOptions::new());
+    let open = Rc::new(Cell::new(false));
+    let task = Rc::new(RefCell::new(None));
+    let gate = Gate {
+        inner: a,
+        open: open.clone(),
+        task: task.clone(),
+    };
+    let client = builder.from_io(&handle, gate).into_client();
+    Endpoint::from_io(&handle, b).serve(
+        &handle,
+        handler_fn(
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(Value::Nil),
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(()),
+        ),
+    );
+
+    // fill the write buffer, so that the following messages are queued in their lanes.
+    let plug = client.notify("plug", vec![Value::Binary(vec![0; 64 * 1024])]);
+    core.turn(Some(Duration::from_millis(10)));
+    let mut acks = vec![plug];
+    let mut responses = Vec::new();
+    for i in 0..REQUESTS {
+        responses.push(client.request("request", vec![Value::from(i)]));
+        for j in 0..WEIGHT {
+            acks.push(client.notify("notify", vec![Value::from(j)]));
+        }
+    }
+    for _ in 0..10 {
+        core.turn(Some(Duration::from_millis(1)));
+    }
+
+    open.set(true);
+    if let Some(task) = task.borrow_mut().take() {
+        task.notify();
+    }
+    core.run(join_all(responses).join(join_all(acks))).unwrap();
+
+    // each request is preceded by a full round of notifications.
+    let sent = sent.borrow();
+    let requests: Vec<_> = sent.iter()
+        .enumerate()
+        .filter(|&(_, &s)| s == Seen::Request)
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(requests.len(), REQUESTS, "{:?}", *sent);
+    assert!(requests[0] >= WEIGHT, "{:?}", *sent);
+    for pair in requests.windows(2) {
+        assert_eq!(pair[1] - pair[0], WEIGHT + 1, "{:?}", *sent);
+    }
+}
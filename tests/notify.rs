@@ -0,0 +1,54 @@
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+use std::time::Duration;
+use futures::{Future, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use msgpack_rpc::{handler_fn, Client, EndpointBuilder, RequestContext, Value};
+use msgpack_rpc::io::PipeOptions;
+use tokio_core::reactor::{Core, Timeout};
+
+const COUNT: usize = 10;
+
+#[test]
+fn dropped_acks_deliver_notifications() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (server, client) = EndpointBuilder::new()
+        .notification_capacity(1)
+        .pair(&handle, PipeOptions::new());
+
+    let (tx, rx) = mpsc::unbounded();
+    server.serve(
+        &handle,
+        handler_fn(
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(Value::Nil),
+            move |_: &str, params: Value, _: &Client, _: &RequestContext| {
+                tx.unbounded_send(params).unwrap();
+                Ok(())
+            },
+        ),
+    );
+
+    // the event loop is not running, so the queue is full after the first notifications.
+    let client = client.into_client();
+    for i in 0..COUNT {
+        drop(client.notify("push", vec![Value::from(i)]));
+    }
+
+    let received = rx.take(COUNT as u64).collect();
+    let timer = Timeout::new(Duration::from_secs(5), &handle).unwrap();
+    let received = match core.run(received.select2(timer)) {
+        Ok(Either::A((received, _))) => received,
+        _ => panic!("the notifications have not been delivered"),
+    };
+    // the notifications queued in the background are not ordered.
+    let mut received: Vec<_> = received
+        .iter()
+        .map(|params| params[0].as_u64().unwrap() as usize)
+        .collect();
+    received.sort();
+    assert_eq!(received, (0..COUNT).collect::<Vec<_>>());
+}
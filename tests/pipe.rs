@@ -0,0 +1,166 @@
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+use futures::Future;
+use futures::future::{lazy, Either};
+use msgpack_rpc::{handler_fn, Client, EndpointBuilder, RequestContext, Value};
+use msgpack_rpc::io::{pipe, Fault, PipeOptions};
+use msgpack_rpc::tap::Direction;
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+/// `[1, 2, 3]` and `[4]`, encoded in MessagePack.
+const FIRST: &[u8] = &[0x93, 0x01, 0x02, 0x03];
+const SECOND: &[u8] = &[0x91, 0x04];
+
+/// Read all bytes available in the stream without blocking, with the size of each read.
+fn read_available<R: Read>(
+    core: &mut Core,
+    r: &mut R,
+    buf_size: usize,
+) -> (Vec<u8>, Vec<usize>) {
+    // the stream has to be read in a task, which is notified when more bytes arrive.
+    core.run(lazy(|| -> Result<_, ()> {
+        let mut bytes = Vec::new();
+        let mut reads = Vec::new();
+        let mut buf = vec![0; buf_size];
+        loop {
+            match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    bytes.extend_from_slice(&buf[..n]);
+                    reads.push(n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+        Ok((bytes, reads))
+    })).unwrap()
+}
+
+/// Create a client connected to a server which responds with the parameters of each request.
+fn echo(handle: &Handle, options: PipeOptions) -> Client {
+    let (client, server) = EndpointBuilder::new().pair(handle, options);
+    server.serve(
+        handle,
+        handler_fn(
+            |_: &str, params: Value, _: &Client, _: &RequestContext| Ok(params),
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(()),
+        ),
+    );
+    client.into_client()
+}
+
+/// Run the future, and return `None` if it is not resolved within the duration.
+fn run_within<F: Future>(core: &mut Core, f: F, timeout: Duration) -> Option<F::Item> {
+    let timer = Timeout::new(timeout, &core.handle()).unwrap();
+    match core.run(f.select2(timer)) {
+        Ok(Either::A((item, _))) => Some(item),
+        Ok(Either::B(_)) => None,
+        Err(_) => panic!("the future has failed"),
+    }
+}
+
+#[test]
+fn latency_delays_each_frame() {
+    let mut core = Core::new().unwrap();
+    let client = echo(&core.handle(), PipeOptions::new().latency(Duration::from_millis(50)));
+
+    let start = Instant::now();
+    let res = core.run(client.request("echo", vec![Value::from(1)])).unwrap();
+    assert_eq!(res, Ok(Value::Array(vec![Value::from(1)])));
+    // the request and the response are delayed respectively.
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn chunk_size_limits_each_read() {
+    let mut core = Core::new().unwrap();
+    let (mut a, mut b) = pipe(&core.handle(), PipeOptions::new().chunk_size(3));
+
+    a.write_all(FIRST).unwrap();
+    a.write_all(SECOND).unwrap();
+    let (bytes, reads) = read_available(&mut core, &mut b, 16);
+    assert_eq!(bytes, [FIRST, SECOND].concat());
+    // a read never spans two frames.
+    assert_eq!(reads, [3, 1, 2]);
+}
+
+#[test]
+fn partial_reads_are_reassembled() {
+    let mut core = Core::new().unwrap();
+    let client = echo(&core.handle(), PipeOptions::new().chunk_size(1));
+
+    let params = vec![Value::from("x".repeat(1000)), Value::from(42)];
+    let res = core.run(client.request("echo", params.clone())).unwrap();
+    assert_eq!(res, Ok(Value::Array(params)));
+}
+
+#[test]
+fn dropped_frames_are_never_delivered() {
+    let mut core = Core::new().unwrap();
+    let options = PipeOptions::new().fault(|direction, index| match (direction, index) {
+        (Direction::Outbound, 0) => Fault::Drop,
+        _ => Fault::Deliver,
+    });
+    let (mut a, mut b) = pipe(&core.handle(), options);
+
+    a.write_all(FIRST).unwrap();
+    a.write_all(SECOND).unwrap();
+    b.write_all(FIRST).unwrap();
+    assert_eq!(read_available(&mut core, &mut b, 16).0, SECOND);
+    assert_eq!(read_available(&mut core, &mut a, 16).0, FIRST);
+}
+
+#[test]
+fn dropped_request_gets_no_response() {
+    let mut core = Core::new().unwrap();
+    let client = echo(
+        &core.handle(),
+        PipeOptions::new().fault(|direction, index| match (direction, index) {
+            (Direction::Outbound, 0) => Fault::Drop,
+            _ => Fault::Deliver,
+        }),
+    );
+
+    let dropped = client.request("echo", vec![Value::from(1)]);
+    let res = core.run(client.request("echo", vec![Value::from(2)])).unwrap();
+    assert_eq!(res, Ok(Value::Array(vec![Value::from(2)])));
+    assert!(run_within(&mut core, dropped, Duration::from_millis(100)).is_none());
+}
+
+#[test]
+fn truncated_frame_is_joined_with_the_next_frame() {
+    let mut core = Core::new().unwrap();
+    let options = PipeOptions::new().fault(|direction, index| match (direction, index) {
+        (Direction::Outbound, 0) => Fault::Truncate(2),
+        _ => Fault::Deliver,
+    });
+    let (mut a, mut b) = pipe(&core.handle(), options);
+
+    a.write_all(FIRST).unwrap();
+    a.write_all(SECOND).unwrap();
+    // the frame boundary is lost, so the reader sees the valid frame as a part of the
+    // truncated one.
+    assert_eq!(read_available(&mut core, &mut b, 16).0, [&FIRST[..2], SECOND].concat());
+}
+
+#[test]
+fn truncated_request_swallows_the_next_request() {
+    let mut core = Core::new().unwrap();
+    let client = echo(
+        &core.handle(),
+        PipeOptions::new().fault(|direction, index| match (direction, index) {
+            (Direction::Outbound, 0) => Fault::Truncate(2),
+            _ => Fault::Deliver,
+        }),
+    );
+
+    let truncated = client.request("echo", vec![Value::from(1)]);
+    let next = client.request("echo", vec![Value::from(2)]);
+    let both = truncated.join(next);
+    assert!(run_within(&mut core, both, Duration::from_millis(100)).is_none());
+}
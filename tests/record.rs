@@ -0,0 +1,194 @@
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::rc::Rc;
+use futures::future::{join_all, ok, FutureResult};
+use msgpack_rpc::{handler_fn, Client, Connection, EndpointBuilder, Endpoint, Handler,
+                  RequestContext, Value};
+use msgpack_rpc::io::{pipe, PipeOptions};
+use msgpack_rpc::record::{RecordedMessage, Recorder, Recording};
+use msgpack_rpc::tap::Direction;
+use tokio_core::reactor::Core;
+
+/// A writer which can be read while a recorder owns it.
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Respond with the parameters, or with a `nil` error to the method `fail`.
+fn echo(method: &str, params: Value, _: &Client, _: &RequestContext) -> Result<Value, Value> {
+    match method {
+        "fail" => Err(Value::Nil),
+        _ => Ok(params),
+    }
+}
+
+fn ignore(_: &str, _: Value, _: &Client, _: &RequestContext) -> Result<(), ()> {
+    Ok(())
+}
+
+/// A handler which counts the requests of each connection in its extensions.
+#[derive(Default)]
+struct Counter {
+    torn_down: Rc<Cell<usize>>,
+}
+
+impl Handler for Counter {
+    type RequestFuture = FutureResult<Value, Value>;
+    type NotifyFuture = FutureResult<(), ()>;
+
+    fn handle_request(
+        &self,
+        _: &str,
+        _: Value,
+        _: &Client,
+        ctx: &RequestContext,
+    ) -> Self::RequestFuture {
+        let mut extensions = ctx.extensions_mut();
+        let count = extensions.get::<u64>().cloned().unwrap_or(0) + 1;
+        extensions.insert(count);
+        ok(Value::from(count))
+    }
+
+    fn handle_notification(
+        &self,
+        _: &str,
+        _: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::NotifyFuture {
+        ok(())
+    }
+
+    fn on_teardown(&self, _: &Connection) {
+        self.torn_down.set(self.torn_down.get() + 1);
+    }
+}
+
+/// Serve two connections with one recorder, and send the same requests over both of them.
+fn record_with<H: Handler, F: Fn() -> H>(handler: F, methods: &[&str]) -> Recording {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let buf = SharedBuf::default();
+    let server = EndpointBuilder::new().tap(Recorder::new(buf.clone()));
+
+    let mut requests = Vec::new();
+    for i in 0..2 {
+        let (a, b) = pipe(&handle, PipeOptions::new());
+        server.from_io(&handle, a).serve(&handle, handler());
+        let client = Endpoint::from_io(&handle, b).into_client();
+        for method in methods {
+            let params = match *method {
+                "echo" => vec![Value::from(i)],
+                _ => Vec::new(),
+            };
+            requests.push(client.request(*method, params));
+        }
+    }
+    core.run(join_all(requests)).unwrap();
+
+    let bytes = buf.0.borrow().clone();
+    Recording::from_reader(&bytes[..]).unwrap()
+}
+
+fn record() -> Recording {
+    record_with(|| handler_fn(echo, ignore), &["echo", "fail"])
+}
+
+#[test]
+fn entries_are_keyed_by_connection() {
+    let recording = record();
+    let mut connections: Vec<_> = recording.entries().iter().map(|e| e.connection).collect();
+    connections.sort();
+    connections.dedup();
+    assert_eq!(connections.len(), 2);
+
+    // both connections use the same message IDs.
+    for &conn in &connections {
+        let ids: Vec<_> = recording
+            .entries()
+            .iter()
+            .filter(|e| e.connection == conn && e.direction == Direction::Inbound)
+            .map(|e| match e.message {
+                RecordedMessage::Request { msgid, .. } => msgid,
+                ref msg => panic!("unexpected message: {:?}", msg),
+            })
+            .collect();
+        assert_eq!(ids, [0, 1]);
+    }
+}
+
+#[test]
+fn nil_errors_are_recorded_as_errors() {
+    let recording = record();
+    let errors = recording
+        .entries()
+        .iter()
+        .filter(|e| match e.message {
+            RecordedMessage::Response { result: Err(Value::Nil), .. } => true,
+            _ => false,
+        })
+        .count();
+    assert_eq!(errors, 2);
+}
+
+#[test]
+fn replay_matches_the_recorded_handler() {
+    let recording = record();
+    let mut core = Core::new().unwrap();
+    let mismatches = core.run(recording.replay(&core.handle(), handler_fn(echo, ignore)))
+        .unwrap();
+    assert_eq!(mismatches, []);
+}
+
+#[test]
+fn replay_reports_changed_responses() {
+    let recording = record();
+    let mut core = Core::new().unwrap();
+    // a success is no longer mistaken for the recorded nil error.
+    let handler = handler_fn(
+        |_: &str, params: Value, _: &Client, _: &RequestContext| Ok(params),
+        ignore,
+    );
+    let mismatches = core.run(recording.replay(&core.handle(), handler)).unwrap();
+    assert_eq!(mismatches.len(), 2);
+    for mismatch in &mismatches {
+        assert_eq!(mismatch.method, "fail");
+        assert_eq!(mismatch.expected, Some(Err(Value::Nil)));
+        assert_eq!(mismatch.actual, Ok(Value::Array(vec![])));
+    }
+    assert!(mismatches[0].connection != mismatches[1].connection);
+}
+
+#[test]
+fn replay_separates_connections() {
+    let recording = record_with(Counter::default, &["count", "count"]);
+    let mut core = Core::new().unwrap();
+    let handler = Counter::default();
+    let torn_down = handler.torn_down.clone();
+
+    // the counts of each connection are not shared with the other.
+    let mismatches = core.run(recording.replay(&core.handle(), handler)).unwrap();
+    assert_eq!(mismatches, []);
+    assert_eq!(torn_down.get(), 2);
+}
+
+#[test]
+fn entries_without_connection_are_rejected() {
+    let entry = [0x93, 0x00, 0x00, 0x94, 0x00, 0x00, 0xa1, b'f', 0x90];
+    let err = Recording::from_reader(&entry[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,202 @@
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use futures::{Future, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use msgpack_rpc::{handler_fn, Client, Connection, Endpoint, Handler, HandlerFactory,
+                  RequestContext, Value};
+use msgpack_rpc::io::{Overflow, Server};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use tokio_io::io::read_to_end;
+
+/// Start the server on a TCP port chosen by the OS, and return its address.
+fn listen<F: HandlerFactory>(handle: &Handle, server: Server<F>) -> SocketAddr {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let incoming = listener.incoming().map(|(sock, addr)| (sock, Some(addr)));
+    let (serve, _) = server.serve_incoming(handle, incoming);
+    handle.spawn(serve.map_err(|err| panic!("{}", err)));
+    addr
+}
+
+/// Run the future, and return `None` if it is not resolved within the duration.
+fn run_within<F: Future>(core: &mut Core, f: F, timeout: Duration) -> Option<F::Item> {
+    let timer = Timeout::new(timeout, &core.handle()).unwrap();
+    match core.run(f.select2(timer)) {
+        Ok(Either::A((item, _))) => Some(item),
+        Ok(Either::B(_)) => None,
+        Err(_) => panic!("the future has failed"),
+    }
+}
+
+fn sleep(core: &mut Core, duration: Duration) {
+    let timer = Timeout::new(duration, &core.handle()).unwrap();
+    core.run(timer).unwrap();
+}
+
+/// Connect to the server, and return whether the server closes the connection within a
+/// second without receiving anything.
+fn closed_by_server(core: &mut Core, addr: &SocketAddr) -> bool {
+    let stream = core.run(TcpStream::connect(addr, &core.handle())).unwrap();
+    let eof = read_to_end(stream, Vec::new()).map(|(_, bytes)| bytes.is_empty());
+    run_within(core, eof, Duration::from_secs(1)).unwrap_or(false)
+}
+
+/// A handler which responds to every request with `"pong"`, and takes 300 milliseconds to
+/// handle the method `sleep`.
+struct Pong {
+    handle: Handle,
+}
+
+impl Pong {
+    fn sleep(&self, method: &str) -> Timeout {
+        let duration = match method {
+            "sleep" => Duration::from_millis(300),
+            _ => Duration::from_millis(0),
+        };
+        Timeout::new(duration, &self.handle).unwrap()
+    }
+}
+
+impl Handler for Pong {
+    type RequestFuture = Box<Future<Item = Value, Error = Value>>;
+    type NotifyFuture = Box<Future<Item = (), Error = ()>>;
+
+    fn handle_request(
+        &self,
+        method: &str,
+        _: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::RequestFuture {
+        Box::new(self.sleep(method).map(|()| Value::from("pong")).map_err(|_| Value::Nil))
+    }
+
+    fn handle_notification(
+        &self,
+        method: &str,
+        _: Value,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Self::NotifyFuture {
+        Box::new(self.sleep(method).map_err(|_| ()))
+    }
+}
+
+type PongFactory = Box<Fn(&Connection, &Client) -> Pong>;
+
+fn pong(handle: &Handle) -> Server<PongFactory> {
+    let handle = handle.clone();
+    Server::new(Box::new(move |_: &Connection, _: &Client| Pong { handle: handle.clone() }))
+}
+
+fn ping(core: &mut Core, client: &Client) -> Option<Value> {
+    let res = client.request("ping", Vec::<Value>::new());
+    run_within(core, res, Duration::from_secs(1)).map(|res| res.unwrap())
+}
+
+#[test]
+fn shutdown_waits_for_in_flight_requests() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let incoming = listener.incoming().map(|(sock, addr)| (sock, Some(addr)));
+    let (serve, shutdown) = pong(&handle).serve_incoming(&handle, incoming);
+    let stopped = Rc::new(Cell::new(false));
+    let s = stopped.clone();
+    handle.spawn(serve.map(move |()| s.set(true)).map_err(|err| panic!("{}", err)));
+
+    let client = core.run(Endpoint::connect_tcp(&handle, &addr)).unwrap().into_client();
+    let res = client.request("sleep", Vec::<Value>::new());
+    sleep(&mut core, Duration::from_millis(50));
+    shutdown.shutdown();
+    sleep(&mut core, Duration::from_millis(50));
+
+    // the listener is closed, while the request is still in flight.
+    assert!(core.run(TcpStream::connect(&addr, &handle)).is_err());
+    assert!(!stopped.get());
+
+    let res = run_within(&mut core, res, Duration::from_secs(1));
+    assert_eq!(res.map(|res| res.unwrap()), Some(Value::from("pong")));
+    sleep(&mut core, Duration::from_millis(50));
+    assert!(stopped.get());
+}
+
+#[test]
+fn connections_over_the_limit_are_rejected() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = listen(&handle, pong(&handle).max_connections(1, Overflow::Reject));
+
+    let client = core.run(Endpoint::connect_tcp(&handle, &addr)).unwrap().into_client();
+    assert_eq!(ping(&mut core, &client), Some(Value::from("pong")));
+
+    assert!(closed_by_server(&mut core, &addr));
+    // the accepted connection is kept.
+    assert_eq!(ping(&mut core, &client), Some(Value::from("pong")));
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = listen(&handle, pong(&handle).idle_timeout(Duration::from_millis(100)));
+
+    let start = Instant::now();
+    assert!(closed_by_server(&mut core, &addr));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn connections_handling_notifications_are_not_idle() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr = listen(&handle, pong(&handle).idle_timeout(Duration::from_millis(100)));
+
+    let client = core.run(Endpoint::connect_tcp(&handle, &addr)).unwrap().into_client();
+    core.run(client.notify("sleep", Vec::<Value>::new())).unwrap();
+    sleep(&mut core, Duration::from_millis(250));
+    assert_eq!(ping(&mut core, &client), Some(Value::from("pong")));
+}
+
+#[test]
+fn connections_writing_notifications_are_not_idle() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    // push a notification every 50 milliseconds to each peer, which never sends anything.
+    let h = handle.clone();
+    let server = Server::new(move |_: &Connection, client: &Client| {
+        let client = client.clone();
+        let push = Interval::new(Duration::from_millis(50), &h)
+            .unwrap()
+            .take(6)
+            .for_each(move |()| client.notify("push", Vec::<Value>::new()));
+        h.spawn(push.map_err(|_| ()));
+        Pong { handle: h.clone() }
+    });
+    let addr = listen(&handle, server.idle_timeout(Duration::from_millis(100)));
+
+    let (tx, rx) = mpsc::unbounded();
+    core.run(Endpoint::connect_tcp(&handle, &addr)).unwrap().serve(
+        &handle,
+        handler_fn(
+            |_: &str, _: Value, _: &Client, _: &RequestContext| Ok(Value::Nil),
+            move |method: &str, _: Value, _: &Client, _: &RequestContext| {
+                tx.unbounded_send(method.to_owned()).unwrap();
+                Ok(())
+            },
+        ),
+    );
+    let pushed = run_within(&mut core, rx.take(6).collect(), Duration::from_secs(2));
+    assert_eq!(pushed.map(|pushed| pushed.len()), Some(6));
+}
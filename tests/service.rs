@@ -0,0 +1,159 @@
+#![cfg(feature = "with-serde")]
+
+#[macro_use]
+extern crate msgpack_rpc;
+extern crate futures;
+extern crate tokio_core;
+
+use futures::{Future, Stream};
+use futures::future::ok;
+use futures::sync::mpsc;
+use msgpack_rpc::{Client, Endpoint, HandlerError, RequestContext, Value};
+use tokio_core::reactor::Core;
+
+rpc_service! {
+    pub trait Calc {
+        client: CalcClient,
+        server: CalcServer,
+
+        requests {
+            fn add(a: i64, b: i64) -> i64;
+            fn join(client: String, ctx: String, method: String, params: String) -> String;
+        }
+
+        notifications {
+            fn log(message: String);
+        }
+    }
+}
+
+struct CalcImpl {
+    logs: mpsc::UnboundedSender<String>,
+}
+
+impl Calc for CalcImpl {
+    fn add(
+        &self,
+        a: i64,
+        b: i64,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Box<Future<Item = i64, Error = Value>> {
+        Box::new(ok(a + b))
+    }
+
+    fn join(
+        &self,
+        client: String,
+        ctx: String,
+        method: String,
+        params: String,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Box<Future<Item = String, Error = Value>> {
+        Box::new(ok(format!("{}/{}/{}/{}", client, ctx, method, params)))
+    }
+
+    fn log(
+        &self,
+        message: String,
+        _: &Client,
+        _: &RequestContext,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let _ = self.logs.unbounded_send(message);
+        Box::new(ok(()))
+    }
+}
+
+fn setup(core: &Core) -> (CalcClient, mpsc::UnboundedReceiver<String>) {
+    let handle = core.handle();
+    let (server, client) = Endpoint::pair(&handle);
+    let (tx, rx) = mpsc::unbounded();
+    server.serve(&handle, CalcServer(CalcImpl { logs: tx }));
+    (CalcClient::new(client.into_client()), rx)
+}
+
+#[test]
+fn typed_request() {
+    let mut core = Core::new().unwrap();
+    let (calc, _) = setup(&core);
+
+    assert_eq!(core.run(calc.add(1, 2)).unwrap(), Ok(3));
+}
+
+#[test]
+fn arguments_named_like_generated_identifiers() {
+    let mut core = Core::new().unwrap();
+    let (calc, _) = setup(&core);
+
+    let res = core.run(calc.join(
+        "a".to_owned(),
+        "b".to_owned(),
+        "c".to_owned(),
+        "d".to_owned(),
+    ));
+    assert_eq!(res.unwrap(), Ok("a/b/c/d".to_owned()));
+}
+
+#[test]
+fn extra_arguments_are_rejected() {
+    let mut core = Core::new().unwrap();
+    let (calc, _) = setup(&core);
+
+    let res = core.run(calc.client().request("add", vec![
+        Value::from(1),
+        Value::from(2),
+        Value::from(3),
+    ])).unwrap();
+    assert_eq!(
+        res,
+        Err(Value::from("Too many arguments: expected 2, given 3"))
+    );
+}
+
+#[test]
+fn unknown_method_is_rejected() {
+    let mut core = Core::new().unwrap();
+    let (calc, _) = setup(&core);
+
+    let res = core.run(calc.client().request("sub", vec![Value::from(1)]))
+        .unwrap();
+    assert!(res.is_err());
+}
+
+#[test]
+fn typed_notification() {
+    let mut core = Core::new().unwrap();
+    let (calc, logs) = setup(&core);
+
+    core.run(calc.log("hello".to_owned())).unwrap();
+    let (message, _) = core.run(logs.into_future()).ok().unwrap();
+    assert_eq!(message, Some("hello".to_owned()));
+}
+
+#[test]
+fn malformed_notifications_are_reported() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (server, client) = Endpoint::pair(&handle);
+    let (tx, _) = mpsc::unbounded();
+    let (tx_err, rx_err) = mpsc::unbounded();
+    server
+        .on_error(move |err| {
+            let _ = tx_err.unbounded_send(err);
+        })
+        .serve(&handle, CalcServer(CalcImpl { logs: tx }));
+    let client = client.into_client();
+
+    core.run(client.notify("sub", vec![Value::from(1)])).unwrap();
+    core.run(client.notify("log", vec![Value::from(1)])).unwrap();
+    let errors = core.run(rx_err.take(2).collect()).unwrap();
+    let methods: Vec<_> = errors
+        .into_iter()
+        .map(|err| match err {
+            HandlerError::NotificationFailed { method } => method,
+            err => panic!("unexpected error: {}", err),
+        })
+        .collect();
+    assert_eq!(methods, ["sub", "log"]);
+}
@@ -0,0 +1,76 @@
+//! The writer thread of the standard output, tested by replacing the standard output of this
+//! process with a pipe whose reader has been closed.
+
+#![cfg(unix)]
+
+extern crate futures;
+extern crate libc;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{self, Write};
+use std::time::Duration;
+use futures::Future;
+use futures::future::{poll_fn, Either};
+use msgpack_rpc::io::StdioStream;
+use tokio_core::reactor::{Core, Timeout};
+use tokio_io::AsyncWrite;
+
+/// Replaces the standard output with a pipe without its reader, until dropped.
+struct BrokenStdout {
+    saved: libc::c_int,
+}
+
+impl BrokenStdout {
+    fn new() -> Self {
+        // the output of the test harness must not be written to the pipe.
+        io::stdout().flush().unwrap();
+        unsafe {
+            let mut fds = [0; 2];
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            libc::close(fds[0]);
+            let saved = libc::dup(1);
+            libc::dup2(fds[1], 1);
+            libc::close(fds[1]);
+            BrokenStdout { saved }
+        }
+    }
+}
+
+impl Drop for BrokenStdout {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dup2(self.saved, 1);
+            libc::close(self.saved);
+        }
+    }
+}
+
+/// Shut down the stream, and return `None` if it doesn't complete within a second.
+fn shutdown(core: &mut Core, stream: &mut StdioStream) -> Option<io::Result<()>> {
+    let timer = Timeout::new(Duration::from_secs(1), &core.handle()).unwrap();
+    let shutdown = poll_fn(|| stream.shutdown()).then(Ok::<_, ()>);
+    match core.run(shutdown.select2(timer.then(|_| Ok::<_, ()>(())))) {
+        Ok(Either::A((res, _))) => Some(res),
+        Ok(Either::B(_)) => None,
+        Err(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn write_errors_are_kept() {
+    let _stdout = BrokenStdout::new();
+    let mut core = Core::new().unwrap();
+    let mut stream = StdioStream::new(1024);
+    assert_eq!(stream.write(b"hello\n").unwrap(), 6);
+
+    // the error is reported to every subsequent call, instead of hanging.
+    for _ in 0..2 {
+        let err = shutdown(&mut core, &mut stream)
+            .expect("the shutdown has not completed")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+    assert!(stream.write(b"world\n").is_err());
+}
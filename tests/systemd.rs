@@ -0,0 +1,171 @@
+//! Socket activation, tested by running each activated test in a child process which
+//! receives the sockets from 3, as systemd passes them.
+//!
+//! The tests run in child processes are ignored, so that they are only run by their parents.
+
+#![cfg(unix)]
+
+extern crate futures;
+extern crate libc;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+mod common;
+
+use std::env;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use futures::Future;
+use msgpack_rpc::{Endpoint, Value};
+use msgpack_rpc::io::{listen_fds, Server};
+use tokio_core::reactor::Core;
+use common::pong;
+
+/// The number of descriptors passed to the child process.
+const ACTIVATED_FDS: &str = "MSGPACK_RPC_TEST_ACTIVATED_FDS";
+/// The address of the listening socket passed to the child process.
+const ACTIVATED_ADDR: &str = "MSGPACK_RPC_TEST_ACTIVATED_ADDR";
+
+/// Create a command to run the ignored test `name` in a child process.
+fn child(name: &str) -> Command {
+    let mut cmd = Command::new(env::current_exe().unwrap());
+    cmd.args(["--ignored", "--exact", name, "--test-threads", "1"]);
+    cmd
+}
+
+/// Run the test `name` in a child process, with `fds` passed as socket activation does.
+fn activate(name: &str, fds: &[RawFd], addr: Option<SocketAddr>) {
+    let mut cmd = child(name);
+    cmd.env(ACTIVATED_FDS, fds.len().to_string());
+    if let Some(addr) = addr {
+        cmd.env(ACTIVATED_ADDR, addr.to_string());
+    }
+    let fds = fds.to_vec();
+    unsafe {
+        cmd.pre_exec(move || {
+            // move the descriptors out of the way first, so that none of them is overwritten.
+            let start = 3 + fds.len() as RawFd;
+            let mut moved = Vec::new();
+            for &fd in &fds {
+                let fd = libc::fcntl(fd, libc::F_DUPFD, start);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                moved.push(fd);
+            }
+            for (i, &fd) in moved.iter().enumerate() {
+                if libc::dup2(fd, 3 + i as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::close(fd);
+            }
+            Ok(())
+        });
+    }
+    let status = cmd.status().unwrap();
+    assert!(status.success(), "the activated test {} has failed", name);
+}
+
+/// Set the variables of socket activation for this process.
+///
+/// This fails unless the test is run by `activate()`.
+fn activated() {
+    let n = env::var(ACTIVATED_FDS).expect("the test must be run by activate()");
+    env::set_var("LISTEN_PID", unsafe { libc::getpid() }.to_string());
+    env::set_var("LISTEN_FDS", n);
+    env::set_var("LISTEN_FDNAMES", "rpc");
+}
+
+#[test]
+fn listen_fds_takes_passed_descriptors() {
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    activate(
+        "activated_listen_fds",
+        &[tcp.as_raw_fd(), udp.as_raw_fd()],
+        None,
+    );
+}
+
+#[test]
+#[ignore]
+fn activated_listen_fds() {
+    activated();
+    let fds = listen_fds().unwrap();
+    assert_eq!(fds, [3, 4]);
+    for &fd in &fds {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        assert!(env::var(name).is_err(), "{} is not removed", name);
+    }
+    // the descriptors are taken only once.
+    assert_eq!(listen_fds().unwrap(), []);
+}
+
+#[test]
+fn listen_fds_ignores_other_processes() {
+    // the variables of socket activation are inherited by the child, but not its PID.
+    let status = child("not_activated_listen_fds")
+        .env("LISTEN_PID", unsafe { libc::getpid() }.to_string())
+        .env("LISTEN_FDS", "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+#[ignore]
+fn not_activated_listen_fds() {
+    assert!(env::var("LISTEN_FDS").is_ok(), "the test must be run by its parent");
+    assert_eq!(listen_fds().unwrap(), []);
+    assert!(env::var("LISTEN_FDS").is_err());
+}
+
+#[test]
+fn serve_systemd_accepts_on_passed_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    activate("activated_serve", &[listener.as_raw_fd()], Some(addr));
+}
+
+#[test]
+#[ignore]
+fn activated_serve() {
+    activated();
+    let addr: SocketAddr = env::var(ACTIVATED_ADDR).unwrap().parse().unwrap();
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (server, _) = Server::new(pong).serve_systemd(&handle).unwrap();
+    handle.spawn(server.map_err(|err| panic!("{}", err)));
+
+    let client = core.run(Endpoint::connect_tcp(&handle, &addr))
+        .unwrap()
+        .into_client();
+    let res = core.run(client.request("ping", Vec::<Value>::new())).unwrap();
+    assert_eq!(res, Ok(Value::from("pong")));
+}
+
+#[test]
+fn serve_systemd_rejects_connected_socket() {
+    // units with `Accept=yes` pass a connected socket for each connection.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (conn, _) = listener.accept().unwrap();
+    activate("activated_connected", &[conn.as_raw_fd()], None);
+}
+
+#[test]
+#[ignore]
+fn activated_connected() {
+    activated();
+    let core = Core::new().unwrap();
+    let err = Server::new(pong).serve_systemd(&core.handle()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("not a listening socket"), "{}", err);
+}
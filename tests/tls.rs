@@ -0,0 +1,126 @@
+#![cfg(feature = "tls")]
+
+extern crate futures;
+extern crate msgpack_rpc;
+extern crate tokio_core;
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::Future;
+use msgpack_rpc::{Client, Connection, Endpoint, RequestContext, Value};
+use msgpack_rpc::io::{load_certs, load_private_key, PeerCertificates, Server};
+use msgpack_rpc::rustls::{AllowAnyAuthenticatedClient, ClientConfig, RootCertStore,
+                          ServerConfig};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use common::FnHandler;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(fixture("ca.pem")).unwrap() {
+        roots.add(&cert).unwrap();
+    }
+    roots
+}
+
+fn server_config() -> Arc<ServerConfig> {
+    let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots()));
+    config.set_single_cert(
+        load_certs(fixture("server.pem")).unwrap(),
+        load_private_key(fixture("server-key.pem")).unwrap(),
+    );
+    Arc::new(config)
+}
+
+fn client_config() -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = roots();
+    config.set_single_client_cert(
+        load_certs(fixture("client.pem")).unwrap(),
+        load_private_key(fixture("client-key.pem")).unwrap(),
+    );
+    Arc::new(config)
+}
+
+/// Bind a listener to a port chosen by the OS, and return it with its address.
+fn bind(handle: &Handle) -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+/// The DER encoding of a certificate in the fixtures.
+fn der(name: &str) -> Vec<u8> {
+    load_certs(fixture(name)).unwrap().remove(0).0
+}
+
+/// A handler which responds with the certificate of the client.
+fn whoami(_: &Connection, _: &Client) -> FnHandler {
+    fn request(_: &str, _: Value, _: &Client, ctx: &RequestContext) -> Result<Value, Value> {
+        let certs = ctx.extensions().get::<PeerCertificates>().cloned();
+        match certs.as_ref().and_then(|certs| certs.end_entity()) {
+            Some(der) => Ok(Value::Binary(der.to_vec())),
+            None => Err(Value::from("no client certificate")),
+        }
+    }
+    common::responder(request)
+}
+
+#[test]
+fn request_over_mutual_tls() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (listener, addr) = bind(&handle);
+
+    let (server, _) = Server::new(whoami).serve_tls_listener(&handle, listener, server_config());
+    handle.spawn(server.map_err(|err| panic!("{}", err)));
+
+    let endpoint = core.run(Endpoint::connect_tls(
+        &handle,
+        &addr,
+        "localhost",
+        client_config(),
+    )).unwrap();
+    let server_cert = endpoint
+        .connection()
+        .extensions()
+        .get::<PeerCertificates>()
+        .and_then(|certs| certs.end_entity().map(|der| der.to_vec()));
+    assert_eq!(server_cert, Some(der("server.pem")));
+
+    let res = core.run(endpoint.into_client().request("whoami", Vec::<Value>::new()))
+        .unwrap();
+    assert_eq!(res, Ok(Value::Binary(der("client.pem"))));
+}
+
+#[test]
+fn idle_peers_do_not_block_handshakes() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (listener, addr) = bind(&handle);
+
+    let (server, _) = Server::new(whoami)
+        .handshake_timeout(Duration::from_millis(200))
+        .serve_tls_listener(&handle, listener, server_config());
+    handle.spawn(server.map_err(|err| panic!("{}", err)));
+
+    // occupy every slot of concurrent handshakes with peers which never start them.
+    let idle: Vec<_> = (0..128)
+        .map(|_| core.run(TcpStream::connect(&addr, &handle)).unwrap())
+        .collect();
+
+    let connect = Endpoint::connect_tls(&handle, &addr, "localhost", client_config())
+        .timeout(Duration::from_secs(5));
+    let endpoint = core.run(connect).unwrap();
+    let res = core.run(endpoint.into_client().request("whoami", Vec::<Value>::new()))
+        .unwrap();
+    assert_eq!(res, Ok(Value::Binary(der("client.pem"))));
+    drop(idle);
+}